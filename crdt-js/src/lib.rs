@@ -1,8 +1,10 @@
 mod utils;
 
-use crdt;
-use crdt::clocks::S4Vector;
+use crdt::awareness::{Awareness, AwarenessMessage, PeerState};
+use crdt::clocks::{S4Vector, VectorClock};
+use crdt::cursor::{Cursor, Selection};
 use crdt::data_structure::{Operation, SynchronizedText};
+use crdt::undo::UndoManager;
 use wasm_bindgen::prelude::*;
 
 extern crate serde_json;
@@ -16,78 +18,232 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 #[wasm_bindgen]
 extern "C" {
     fn alert(s: &str);
+
+    #[wasm_bindgen(js_namespace = Date)]
+    fn now() -> f64;
 }
 
+// typing within this many milliseconds is undone at once
+const UNDO_GROUP_INTERVAL: u64 = 1000;
+// peers that didn't send their cursor for this many milliseconds are hidden
+const AWARENESS_TIMEOUT: u64 = 30000;
+
 #[wasm_bindgen]
 pub fn greet() {
     alert("Hello, World!");
 }
 
+fn to_js_error(err: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
 #[wasm_bindgen]
 pub struct TextBoxSynchronizer {
     text: SynchronizedText,
-    cursor_pos: crdt::clocks::S4Vector,
+    selection: Selection,
+    undo: UndoManager,
+    awareness: Awareness,
 }
 #[wasm_bindgen]
 impl TextBoxSynchronizer {
     pub fn new(id: usize) -> TextBoxSynchronizer {
         TextBoxSynchronizer {
             text: SynchronizedText::new(id),
-            cursor_pos: crdt::clocks::S4Vector::root(),
+            selection: Selection::collapsed(Cursor::start()),
+            undo: UndoManager::new(UNDO_GROUP_INTERVAL),
+            awareness: Awareness::new(id, AWARENESS_TIMEOUT),
         }
     }
 
+    pub fn from_snapshot(id: usize, snapshot: &str) -> Result<TextBoxSynchronizer, JsValue> {
+        let snapshot = serde_json::de::from_str(snapshot).map_err(to_js_error)?;
+        Ok(TextBoxSynchronizer {
+            text: SynchronizedText::from_snapshot(id, snapshot).map_err(to_js_error)?,
+            selection: Selection::collapsed(Cursor::start()),
+            undo: UndoManager::new(UNDO_GROUP_INTERVAL),
+            awareness: Awareness::new(id, AWARENESS_TIMEOUT),
+        })
+    }
+
+    pub fn snapshot(&self) -> String {
+        serde_json::to_string(&self.text.snapshot()).unwrap()
+    }
+
     pub fn get_text(&self) -> String {
         self.text.get_text()
     }
 
-    pub fn insert_at_cursor(&mut self, character: char) -> String {
-        let op = self.text.local_insert(self.cursor_pos, character);
-        self.cursor_pos = self.text.get_clock().to_s4vector();
-        serde_json::to_string(&op).unwrap()
+    pub fn insert_at_cursor(&mut self, character: char) -> Result<String, JsValue> {
+        let index = self.get_absolute_cursor_pos();
+        let op = self
+            .text
+            .local_insert(self.position_at(index), character)
+            .map_err(to_js_error)?;
+        self.undo.record(&op, now() as u64);
+        self.set_absolute_cursor_pos(index + 1);
+        Ok(serde_json::to_string(&op).unwrap())
     }
 
-    pub fn remove_at_cursor(&mut self) -> Option<String> {
-        if self.cursor_pos == S4Vector::root() {
-            return None;
+    /// Inserts pasted text with a single operation, `None` if nothing was pasted.
+    pub fn insert_str_at_cursor(&mut self, text: &str) -> Result<Option<String>, JsValue> {
+        let index = self.get_absolute_cursor_pos();
+        let op = self
+            .text
+            .local_insert_str(self.position_at(index), text)
+            .map_err(to_js_error)?;
+        if let Some(op) = &op {
+            self.undo.set_boundary();
+            self.undo.record(op, now() as u64);
+            self.undo.set_boundary();
         }
-        let op = self.text.local_delete(self.cursor_pos);
-        self.cursor_pos = self.text.get_clock().to_s4vector();
-        Some(serde_json::to_string(&op).unwrap())
+        self.set_absolute_cursor_pos(index + text.chars().count());
+        Ok(op.map(|op| serde_json::to_string(&op).unwrap()))
     }
 
-    pub fn apply_remote_operation(&mut self, operation: &str) {
-        let op: Operation = serde_json::de::from_str(operation).unwrap();
-        self.text.apply_operation(&op).unwrap();
+    pub fn remove_at_cursor(&mut self) -> Result<Option<String>, JsValue> {
+        let index = self.get_absolute_cursor_pos();
+        if index == 0 {
+            return Ok(None);
+        }
+        let op = self
+            .text
+            .local_delete(self.position_at(index))
+            .map_err(to_js_error)?;
+        self.undo.record(&op, now() as u64);
+        self.set_absolute_cursor_pos(index - 1);
+        Ok(Some(serde_json::to_string(&op).unwrap()))
     }
 
-    pub fn get_absolute_cursor_pos(&self) -> usize {
-        let mut result = 0;
-        for (pos, c) in self.text.iter() {
-            if c.is_some() {
-                result += 1;
-            }
-            if pos == self.cursor_pos {
-                break;
-            }
+    /// Removes `len` characters starting at `index` with a single operation, `None` if
+    /// nothing was selected.
+    pub fn remove_range(&mut self, index: usize, len: usize) -> Result<Option<String>, JsValue> {
+        let op = self.text.delete_range(index, len).map_err(to_js_error)?;
+        if let Some(op) = &op {
+            self.undo.set_boundary();
+            self.undo.record(op, now() as u64);
+            self.undo.set_boundary();
         }
-        result
+        self.set_absolute_cursor_pos(index);
+        Ok(op.map(|op| serde_json::to_string(&op).unwrap()))
+    }
+
+    /// Reverts the latest local changes, returns the operations to send as JSON array.
+    pub fn undo(&mut self) -> Result<String, JsValue> {
+        let operations = self.undo.undo(&mut self.text).map_err(to_js_error)?;
+        Ok(serde_json::to_string(&operations).unwrap())
+    }
+
+    /// Reapplies the latest undone changes, returns the operations to send as JSON array.
+    pub fn redo(&mut self) -> Result<String, JsValue> {
+        let operations = self.undo.redo(&mut self.text).map_err(to_js_error)?;
+        Ok(serde_json::to_string(&operations).unwrap())
+    }
+
+    pub fn apply_remote_operation(&mut self, operation: &str) -> Result<(), JsValue> {
+        let op: Operation = serde_json::de::from_str(operation).map_err(to_js_error)?;
+        self.text.apply_operation(&op).map_err(to_js_error)?;
+        Ok(())
+    }
+
+    pub fn clock_values(&self) -> String {
+        serde_json::to_string(self.text.get_clock().clock_values()).unwrap()
+    }
+
+    /// Operations a peer with the clock values `clock` is missing as JSON array.
+    pub fn operations_since(&self, clock: &str) -> Result<String, JsValue> {
+        let values: Vec<u32> = serde_json::de::from_str(clock).map_err(to_js_error)?;
+        let mut clock = VectorClock::new(0);
+        clock.merge_remote(&values);
+        let operations = self.text.operations_since(&clock).map_err(to_js_error)?;
+        Ok(serde_json::to_string(&operations).unwrap())
+    }
+
+    pub fn get_absolute_cursor_pos(&self) -> usize {
+        self.text.cursor_index(&self.selection.head).unwrap_or(0)
     }
 
     pub fn set_absolute_cursor_pos(&mut self, pos: usize) {
-        if pos == 0 {
-            self.cursor_pos = S4Vector::root();
-            return;
-        }
-        let mut current_absolute_pos = 0;
-        for (s4_pos, c) in self.text.iter() {
-            if c.is_some() {
-                current_absolute_pos += 1;
-            }
-            if pos == current_absolute_pos {
-                self.cursor_pos = s4_pos;
-                break;
-            }
+        self.set_selection(pos, pos);
+    }
+
+    /// Selects the text between `anchor` and `head`, it stays selected while others edit.
+    pub fn set_selection(&mut self, anchor: usize, head: usize) {
+        if let Ok(selection) = self.text.selection_at(anchor, head) {
+            self.selection = selection;
         }
     }
+
+    pub fn selection_start(&self) -> usize {
+        self.selection_range().0
+    }
+
+    pub fn selection_end(&self) -> usize {
+        self.selection_range().1
+    }
+
+    /// Shows this site to its peers as `user_name` in `colour`, returns the awareness message
+    /// to send as JSON.
+    pub fn set_user(&mut self, user_name: &str, colour: &str) -> String {
+        let message = self.awareness.set_local_state(PeerState {
+            user_name: user_name.to_owned(),
+            colour: colour.to_owned(),
+            selection: Some(self.selection),
+        });
+        serde_json::to_string(&message).unwrap()
+    }
+
+    /// The awareness message with the current selection as JSON, `None` before
+    /// [`TextBoxSynchronizer::set_user`]. Send it after the selection changed and regularly
+    /// within the timeout.
+    pub fn awareness_update(&mut self) -> Option<String> {
+        let mut state = self.awareness.local_state()?.clone();
+        state.selection = Some(self.selection);
+        let message = self.awareness.set_local_state(state);
+        Some(serde_json::to_string(&message).unwrap())
+    }
+
+    /// The awareness message telling peers this site left as JSON.
+    pub fn leave(&mut self) -> String {
+        serde_json::to_string(&self.awareness.leave()).unwrap()
+    }
+
+    pub fn apply_awareness_message(&mut self, message: &str) -> Result<(), JsValue> {
+        let message: AwarenessMessage = serde_json::de::from_str(message).map_err(to_js_error)?;
+        self.awareness.apply_message(&message, now() as u64);
+        Ok(())
+    }
+
+    /// The peers that are still around as JSON array of objects with their `site_id`,
+    /// `user_name`, `colour` and the `start`, `end` and `head` index of their selection.
+    pub fn peer_cursors(&mut self) -> String {
+        self.awareness.remove_stale(now() as u64);
+        let peers: Vec<serde_json::Value> = self
+            .awareness
+            .peers()
+            .into_iter()
+            .filter_map(|(site_id, peer)| {
+                let selection = peer.selection?;
+                let (start, end) = self.text.selection_range(&selection)?;
+                Some(serde_json::json!({
+                    "site_id": site_id,
+                    "user_name": peer.user_name,
+                    "colour": peer.colour,
+                    "start": start,
+                    "end": end,
+                    "head": self.text.cursor_index(&selection.head)?,
+                }))
+            })
+            .collect();
+        serde_json::to_string(&peers).unwrap()
+    }
+}
+
+impl TextBoxSynchronizer {
+    fn selection_range(&self) -> (usize, usize) {
+        self.text.selection_range(&self.selection).unwrap_or((0, 0))
+    }
+
+    fn position_at(&self, index: usize) -> S4Vector {
+        self.text.position_at(index).unwrap_or(S4Vector::root())
+    }
 }
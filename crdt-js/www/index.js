@@ -4,33 +4,110 @@ const port = 3000
 const baseUrl = `http://${host}:${port}`
 
 let textarea = document.getElementById('area')
+let peerList = document.getElementById('peers')
 connectTextarea(textarea)
 
+// cursors are sent again well within the timeout of the other sites
+const AWARENESS_INTERVAL = 10000
+
 
 function applyOp(text, textbox, op) {
     text.apply_remote_operation(op)
     textbox.value = text.get_text();
-    let cursorPos = text.get_absolute_cursor_pos();
-    textbox.setSelectionRange(cursorPos, cursorPos)
+    textbox.setSelectionRange(text.selection_start(), text.selection_end())
+}
+
+function renderPeers(text) {
+    peerList.replaceChildren()
+    for (let peer of JSON.parse(text.peer_cursors())) {
+        let entry = document.createElement('div')
+        entry.style.color = peer.colour
+        let range = peer.start == peer.end ? `${peer.head}` : `${peer.start}-${peer.end}`
+        entry.textContent = `${peer.user_name}: ${range}`
+        peerList.appendChild(entry)
+    }
+}
+
+// local operations made while the socket is closed are sent once it is open again
+function sendOp(synchronizer, op) {
+    if (synchronizer.socket.readyState === WebSocket.OPEN) {
+        synchronizer.socket.send(op)
+    } else {
+        synchronizer.unsent.push(op)
+    }
+}
+
+function sendAwareness(synchronizer) {
+    synchronizer.text.set_selection(textarea.selectionStart, textarea.selectionEnd)
+    let message = synchronizer.text.awareness_update()
+    if (message !== undefined && synchronizer.socket.readyState === WebSocket.OPEN) {
+        synchronizer.socket.send(message)
+    }
 }
 
 async function connectTextarea(textarea) {
     textarea.value = "Connecting..."
     let synchronizer = await connect()
     textarea.value = ""
-    synchronizer.socket.addEventListener('message', (msg) => {
-        synchronizer.text.set_absolute_cursor_pos(textarea.selectionStart)
-        applyOp(synchronizer.text, textarea, msg.data)
-    })
+    let colour = `hsl(${Math.floor(Math.random() * 360)}, 70%, 45%)`
+    synchronizer.socket.send(synchronizer.text.set_user(`User ${synchronizer.id}`, colour))
+    let listen = () => {
+        synchronizer.socket.addEventListener('message', (msg) => {
+            if (JSON.parse(msg.data).op_clock === undefined) {
+                synchronizer.text.apply_awareness_message(msg.data)
+            } else {
+                synchronizer.text.set_selection(textarea.selectionStart, textarea.selectionEnd)
+                applyOp(synchronizer.text, textarea, msg.data)
+            }
+            renderPeers(synchronizer.text)
+        })
+        // only the operations missed while disconnected are sent again
+        synchronizer.socket.addEventListener('close', async () => {
+            synchronizer.socket = await openSocket(synchronizer.id, synchronizer.text.clock_values())
+            for (let op of synchronizer.unsent.splice(0)) {
+                synchronizer.socket.send(op)
+            }
+            listen()
+            sendAwareness(synchronizer)
+        })
+    }
+    listen()
+
+    for (let event of ['keyup', 'mouseup', 'select']) {
+        textarea.addEventListener(event, () => sendAwareness(synchronizer))
+    }
+    setInterval(() => {
+        sendAwareness(synchronizer)
+        renderPeers(synchronizer.text)
+    }, AWARENESS_INTERVAL)
+    window.addEventListener('beforeunload', () => synchronizer.socket.send(synchronizer.text.leave()))
 
 
     textarea.addEventListener('keydown', (event) => {
         synchronizer.text.set_absolute_cursor_pos(textarea.selectionStart)
 
         let op
-        if (event.key == 'Enter') {
+        if ((event.ctrlKey || event.metaKey) && (event.key == 'z' || event.key == 'Z' || event.key == 'y')) {
+            event.preventDefault()
+            let redo = event.key == 'y' || event.shiftKey
+            let ops = JSON.parse(redo ? synchronizer.text.redo() : synchronizer.text.undo())
+            for (let undoOp of ops) {
+                sendOp(synchronizer, JSON.stringify(undoOp))
+            }
+            let cursorPos = synchronizer.text.get_absolute_cursor_pos()
+            textarea.value = synchronizer.text.get_text()
+            textarea.setSelectionRange(cursorPos, cursorPos)
+            return
+        }
+        else if (event.ctrlKey || event.metaKey) {
+            return
+        }
+        else if (event.key == 'Enter') {
             op = synchronizer.text.insert_at_cursor("\n")
         }
+        else if (event.key == 'Backspace' && textarea.selectionEnd > textarea.selectionStart) {
+            op = synchronizer.text.remove_range(textarea.selectionStart, textarea.selectionEnd - textarea.selectionStart)
+        }
         else if (event.key == 'Backspace') {
             op = synchronizer.text.remove_at_cursor()
         }
@@ -41,19 +118,34 @@ async function connectTextarea(textarea) {
         
         if(op == undefined) return
 
-        synchronizer.socket.send(op)
+        sendOp(synchronizer, op)
+    })
+
+    textarea.addEventListener('paste', (event) => {
+        synchronizer.text.set_absolute_cursor_pos(textarea.selectionStart)
+
+        let op = synchronizer.text.insert_str_at_cursor(event.clipboardData.getData('text'))
+        if(op == undefined) return
+
+        sendOp(synchronizer, op)
     })
 }
 
 
+async function openSocket(id, clock) {
+    let query = clock === undefined ? '' : `?clock=${encodeURIComponent(clock)}`
+    let socket = new WebSocket("ws"+ document.location.origin.substring(4)  + `/data-stream/${id}${query}`)
+    let promise = new Promise((resolve) => socket.onopen = () => resolve(socket))
+    return await promise
+}
+
 async function connect() {
     let id = await (fetch(`/register`, { method: 'POST' }).then(v => v.text()))
-    let socket = new WebSocket("ws"+ document.location.origin.substring(4)  + `/data-stream/${id}`)
-    let promise = new Promise((resolve) => socket.onopen = () => resolve(socket))
-    await promise
+    let socket = await openSocket(id)
     return {
         id,
         socket,
+        unsent: [],
         text: wasm.TextBoxSynchronizer.new(id)
     }
 }
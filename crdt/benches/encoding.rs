@@ -0,0 +1,91 @@
+//! Compares the binary encoding of operations with the JSON the web client sends.
+//!
+//! Run with `cargo bench -p crdt --bench encoding`.
+
+use std::time::{Duration, Instant};
+
+use crdt::{
+    data_structure::{Operation, SynchronizedText},
+    encoding::{decode_operations, encode_operations},
+};
+
+// a session of three sites typing words at different places and correcting typos
+fn edit_session(num_words: usize) -> Vec<Operation> {
+    let mut sites: Vec<SynchronizedText> = (0..3).map(SynchronizedText::new).collect();
+    let mut operations = Vec::new();
+    let mut seed: u64 = 42;
+    for word in 0..num_words {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let site = (seed >> 33) as usize % sites.len();
+        let index = (seed >> 40) as usize % (sites[site].len() + 1);
+        let mut new_ops = vec![sites[site]
+            .insert_str_at(index, &format!("word{} ", word))
+            .unwrap()];
+        if word % 5 == 0 {
+            new_ops.extend(sites[site].delete_range(index, 2).unwrap());
+        }
+        for op in &new_ops {
+            for (other, text) in sites.iter_mut().enumerate() {
+                if other != site {
+                    text.apply_operation(op).unwrap();
+                }
+            }
+        }
+        operations.extend(new_ops);
+    }
+    operations
+}
+
+fn measure<R>(iterations: u32, mut f: impl FnMut() -> R) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        std::hint::black_box(f());
+    }
+    start.elapsed() / iterations
+}
+
+fn main() {
+    let iterations = 20;
+    for num_words in [100, 1000, 5000] {
+        let operations = edit_session(num_words);
+
+        let json: Vec<String> = operations
+            .iter()
+            .map(|op| serde_json::to_string(op).unwrap())
+            .collect();
+        let json_size: usize = json.iter().map(String::len).sum();
+        let json_encode = measure(iterations, || {
+            operations
+                .iter()
+                .map(|op| serde_json::to_string(op).unwrap())
+                .collect::<Vec<_>>()
+        });
+        let json_decode = measure(iterations, || {
+            json.iter()
+                .map(|op| serde_json::from_str::<Operation>(op).unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let single: Vec<Vec<u8>> = operations.iter().map(Operation::encode).collect();
+        let single_size: usize = single.iter().map(Vec::len).sum();
+
+        let batch = encode_operations(&operations);
+        let batch_encode = measure(iterations, || encode_operations(&operations));
+        let batch_decode = measure(iterations, || decode_operations(&batch).unwrap());
+
+        println!("{} operations", operations.len());
+        println!(
+            "  json:             {:>9} bytes, encode {:>10?}, decode {:>10?}",
+            json_size, json_encode, json_decode
+        );
+        println!("  binary, single:   {:>9} bytes", single_size);
+        println!(
+            "  binary, batch:    {:>9} bytes, encode {:>10?}, decode {:>10?}",
+            batch.len(),
+            batch_encode,
+            batch_decode
+        );
+    }
+}
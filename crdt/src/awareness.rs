@@ -0,0 +1,178 @@
+//! Who else is editing and where their cursors are. Unlike operations this state is
+//! ephemeral: it is never stored, every site repeats its own state regularly, and peers that
+//! stay silent for too long are dropped.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cursor::Selection;
+
+/// What a site shows the others about itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerState {
+    pub user_name: String,
+    /// Any CSS colour, e.g. `#e6194b`.
+    pub colour: String,
+    pub selection: Option<Selection>,
+}
+
+/// The state of `site_id`, `None` once it left. `counter` grows with every message of the site,
+/// so messages overtaken by newer ones are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AwarenessMessage {
+    pub site_id: usize,
+    pub counter: u32,
+    pub state: Option<PeerState>,
+}
+
+struct Peer {
+    counter: u32,
+    // kept after the peer left, so its older messages are still ignored
+    state: Option<PeerState>,
+    last_seen: u64,
+}
+
+pub struct Awareness {
+    site_id: usize,
+    counter: u32,
+    local: Option<PeerState>,
+    peers: HashMap<usize, Peer>,
+    // peers not heard of for this long are dropped
+    timeout: u64,
+}
+
+impl Awareness {
+    /// Drops peers that sent nothing for `timeout`. Times are supplied by the caller in any
+    /// unit, e.g. milliseconds, and sites should repeat their state more often than that.
+    pub fn new(site_id: usize, timeout: u64) -> Awareness {
+        Awareness {
+            site_id,
+            counter: 0,
+            local: None,
+            peers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn local_state(&self) -> Option<&PeerState> {
+        self.local.as_ref()
+    }
+
+    /// Replaces the state of this site and returns the message announcing it.
+    pub fn set_local_state(&mut self, state: PeerState) -> AwarenessMessage {
+        self.local = Some(state);
+        self.message()
+    }
+
+    /// Announces the state of this site again so peers keep it, `None` if it has none.
+    pub fn renew(&mut self) -> Option<AwarenessMessage> {
+        self.local.is_some().then(|| self.message())
+    }
+
+    /// Clears the state of this site and returns the message telling peers it left.
+    pub fn leave(&mut self) -> AwarenessMessage {
+        self.local = None;
+        self.message()
+    }
+
+    fn message(&mut self) -> AwarenessMessage {
+        self.counter += 1;
+        AwarenessMessage {
+            site_id: self.site_id,
+            counter: self.counter,
+            state: self.local.clone(),
+        }
+    }
+
+    /// Applies a message of a peer received at time `now`, returns whether it was newer than
+    /// what is known about the peer.
+    pub fn apply_message(&mut self, message: &AwarenessMessage, now: u64) -> bool {
+        if message.site_id == self.site_id {
+            return false;
+        }
+        if let Some(peer) = self.peers.get(&message.site_id) {
+            if message.counter <= peer.counter {
+                return false;
+            }
+        }
+        self.peers.insert(
+            message.site_id,
+            Peer {
+                counter: message.counter,
+                state: message.state.clone(),
+                last_seen: now,
+            },
+        );
+        true
+    }
+
+    /// Drops the peers that sent nothing for the timeout before `now` and returns the ids of
+    /// those that hadn't left yet.
+    pub fn remove_stale(&mut self, now: u64) -> Vec<usize> {
+        let mut stale = Vec::new();
+        self.peers.retain(|id, peer| {
+            let is_stale = now.saturating_sub(peer.last_seen) >= self.timeout;
+            if is_stale && peer.state.is_some() {
+                stale.push(*id);
+            }
+            !is_stale
+        });
+        stale.sort();
+        stale
+    }
+
+    /// The states of all peers, ordered by site id.
+    pub fn peers(&self) -> Vec<(usize, &PeerState)> {
+        let mut peers: Vec<(usize, &PeerState)> = self
+            .peers
+            .iter()
+            .filter_map(|(id, peer)| Some((*id, peer.state.as_ref()?)))
+            .collect();
+        peers.sort_by_key(|(id, _)| *id);
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::SynchronizedText;
+
+    fn user(name: &str, selection: Option<Selection>) -> PeerState {
+        PeerState {
+            user_name: name.to_owned(),
+            colour: "#e6194b".to_owned(),
+            selection,
+        }
+    }
+
+    #[test]
+    fn test_awareness() {
+        let mut text = SynchronizedText::new(0);
+        text.insert_str_at(0, "hello").unwrap();
+        let mut alice = Awareness::new(0, 100);
+        let mut bob = Awareness::new(1, 100);
+        assert_eq!(alice.renew(), None);
+
+        let first = alice.set_local_state(user("alice", None));
+        let selection = text.selection_at(1, 3).unwrap();
+        let second = alice.set_local_state(user("alice", Some(selection)));
+        let json = serde_json::to_string(&second).unwrap();
+        assert!(bob.apply_message(&serde_json::from_str(&json).unwrap(), 0));
+        // overtaken messages are ignored
+        assert!(!bob.apply_message(&first, 10));
+        assert_eq!(bob.peers(), vec![(0, &user("alice", Some(selection)))]);
+
+        // renewed states are kept, silent peers are dropped
+        bob.apply_message(&alice.renew().unwrap(), 50);
+        assert!(bob.remove_stale(140).is_empty());
+        assert_eq!(bob.remove_stale(150), vec![0]);
+        assert!(bob.peers().is_empty());
+
+        bob.apply_message(&alice.renew().unwrap(), 200);
+        assert_eq!(bob.peers().len(), 1);
+        bob.apply_message(&alice.leave(), 210);
+        assert!(bob.peers().is_empty());
+    }
+}
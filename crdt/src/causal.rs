@@ -0,0 +1,135 @@
+use crate::{clocks::VectorClock, data_structure::ApplyStatus, Error};
+
+/// An operation stamped with the clock of the site that sent it.
+pub(crate) trait CausalOperation {
+    fn sent_by(&self) -> usize;
+    /// The clock of the site after the operation.
+    fn op_clock(&self) -> &[u32];
+    /// The clock the operation has to be delivered at.
+    fn first_clock(&self) -> Vec<u32>;
+}
+
+/// A replica that integrates the operations of other sites in causal order.
+pub(crate) trait CausalReplica {
+    type Operation: CausalOperation + Clone;
+
+    /// The clock of the replica and the operations it buffers.
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<Self::Operation>);
+
+    /// Integrates an operation that is causally ready.
+    fn integrate(&mut self, operation: &Self::Operation) -> Result<(), Error>;
+}
+
+/// Integrates `operation` and then the buffered operations it unblocks. Operations that are not
+/// causally ready yet are buffered, operations that were integrated before are ignored.
+pub(crate) fn deliver<R: CausalReplica>(
+    replica: &mut R,
+    operation: &R::Operation,
+) -> Result<ApplyStatus, Error> {
+    let (clock, buffer) = replica.causal_parts();
+    if let Some(status) = buffer.admit(clock, operation)? {
+        return Ok(status);
+    }
+    replica.integrate(operation)?;
+    deliver_ready(replica)?;
+    Ok(ApplyStatus::Applied)
+}
+
+/// Integrates the buffered operations that are causally ready, e.g. after a merge. Operations
+/// that fail are skipped, so the later operations of their site aren't blocked, and kept with
+/// their error in [`CausalBuffer::failed`].
+pub(crate) fn deliver_ready<R: CausalReplica>(replica: &mut R) -> Result<(), Error> {
+    loop {
+        let (clock, buffer) = replica.causal_parts();
+        let Some(operation) = buffer.next_ready(clock) else {
+            return Ok(());
+        };
+        if let Err(error) = replica.integrate(&operation) {
+            let (clock, buffer) = replica.causal_parts();
+            clock.merge_remote(operation.op_clock());
+            buffer.failed.push((operation, error));
+        }
+    }
+}
+
+/// Operations that arrived before the operations they depend on.
+pub(crate) struct CausalBuffer<O> {
+    pending: Vec<O>,
+    failed: Vec<(O, Error)>,
+}
+
+impl<O: CausalOperation + Clone> CausalBuffer<O> {
+    pub fn new() -> CausalBuffer<O> {
+        CausalBuffer {
+            pending: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    pub fn pending(&self) -> &[O] {
+        &self.pending
+    }
+
+    /// Buffered operations that failed to integrate once they were ready.
+    pub fn failed(&self) -> &[(O, Error)] {
+        &self.failed
+    }
+
+    /// `None` if `operation` can be integrated now, otherwise what happened to it instead.
+    pub fn admit(
+        &mut self,
+        clock: &VectorClock,
+        operation: &O,
+    ) -> Result<Option<ApplyStatus>, Error> {
+        match clock.check_causality(operation.sent_by(), &operation.first_clock()) {
+            Ok(()) => Ok(None),
+            Err(Error::DuplicateOperation { .. }) => Ok(Some(ApplyStatus::AlreadyApplied)),
+            Err(Error::CausalityGap { .. }) => {
+                if !self
+                    .pending
+                    .iter()
+                    .any(|op| is_same_operation(op, operation))
+                {
+                    self.pending.push(operation.clone());
+                }
+                Ok(Some(ApplyStatus::Buffered))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Removes a buffered operation that is ready to be integrated. Every integrated operation
+    /// may unblock any of the buffered ones, so callers loop until there is none left.
+    pub fn next_ready(&mut self, clock: &VectorClock) -> Option<O> {
+        let idx = self.pending.iter().position(|op| {
+            clock
+                .check_causality(op.sent_by(), &op.first_clock())
+                .is_ok()
+        })?;
+        Some(self.pending.remove(idx))
+    }
+
+    /// Drops buffered operations `clock` already covers, e.g. after merging another replica.
+    pub fn discard_applied(&mut self, clock: &VectorClock) {
+        self.pending.retain(|op| {
+            !matches!(
+                clock.check_causality(op.sent_by(), &op.first_clock()),
+                Err(Error::DuplicateOperation { .. })
+            )
+        });
+    }
+}
+
+/// Every site numbers its operations consecutively, so an operation is known if `clock` has
+/// already seen its number.
+pub(crate) fn is_applied<O: CausalOperation>(clock: &VectorClock, operation: &O) -> bool {
+    matches!(
+        clock.check_causality(operation.sent_by(), &operation.first_clock()),
+        Err(Error::DuplicateOperation { .. })
+    )
+}
+
+fn is_same_operation<O: CausalOperation>(a: &O, b: &O) -> bool {
+    a.sent_by() == b.sent_by()
+        && a.first_clock().get(a.sent_by()) == b.first_clock().get(b.sent_by())
+}
@@ -1,6 +1,8 @@
 use std::cmp::Ordering;
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+use crate::Error;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct S4Vector {
     pub ssn: u32, // session number unused in this implementation
     pub sid: u32,
@@ -21,6 +23,25 @@ impl S4Vector {
     pub fn to_array(&self) -> [u32; 4] {
         [self.ssn, self.sid, self.sum, self.seq]
     }
+
+    /// Position of the element `offset` places behind this one in a run of elements the same
+    /// operation inserted, every element of a run counts as an operation of its own.
+    pub fn run_position(&self, offset: u32) -> S4Vector {
+        S4Vector {
+            sum: self.sum + offset,
+            seq: self.seq + offset,
+            ..*self
+        }
+    }
+
+    /// Like [`S4Vector::run_position`], `None` if the position doesn't fit.
+    pub fn checked_run_position(&self, offset: u32) -> Option<S4Vector> {
+        Some(S4Vector {
+            sum: self.sum.checked_add(offset)?,
+            seq: self.seq.checked_add(offset)?,
+            ..*self
+        })
+    }
 }
 
 impl Ord for S4Vector {
@@ -73,8 +94,20 @@ impl VectorClock {
         }
     }
 
-    pub fn from_parts(site_id: usize, clock: Vec<u32>) -> VectorClock {
-        VectorClock { site_id, clock }
+    /// Fails if the clock has no entry for `site_id` or its sum doesn't fit a position.
+    pub fn from_parts(site_id: usize, clock: Vec<u32>) -> Result<VectorClock, Error> {
+        if clock.len() <= site_id
+            || clock
+                .iter()
+                .try_fold(0u32, |sum, v| sum.checked_add(*v))
+                .is_none()
+        {
+            return Err(Error::MalformedClock {
+                site_id,
+                len: clock.len(),
+            });
+        }
+        Ok(VectorClock { site_id, clock })
     }
 
     pub fn id(&self) -> usize {
@@ -99,7 +132,7 @@ impl VectorClock {
             ssn: 0,
             sid: self.site_id as u32,
             sum,
-            seq: self.clock[self.site_id] as u32,
+            seq: self.clock[self.site_id],
         }
     }
 
@@ -110,6 +143,43 @@ impl VectorClock {
     pub fn clock_values(&self) -> &[u32] {
         &self.clock
     }
+
+    /// Checks whether the operation `sent_by` stamped with `sent_clock_values` is the next one
+    /// this clock can deliver.
+    pub fn check_causality(&self, sent_by: usize, sent_clock_values: &[u32]) -> Result<(), Error> {
+        let sent_clock_value = *sent_clock_values
+            .get(sent_by)
+            .ok_or(Error::MalformedClock {
+                site_id: sent_by,
+                len: sent_clock_values.len(),
+            })?;
+        let received = self.clock_value(sent_by);
+        if sent_clock_value <= received {
+            return Err(Error::DuplicateOperation {
+                sent_by,
+                sequence: sent_clock_value,
+            });
+        }
+        if sent_clock_value != received + 1 {
+            return Err(Error::CausalityGap {
+                site: sent_by,
+                received,
+                required: sent_clock_value - 1,
+            });
+        }
+
+        for (idx, sent_value) in sent_clock_values.iter().enumerate() {
+            // check if any of the clock values is larger than the arriving one
+            if *sent_value > self.clock_value(idx) && idx != sent_by {
+                return Err(Error::CausalityGap {
+                    site: idx,
+                    received: self.clock_value(idx),
+                    required: *sent_value,
+                });
+            }
+        }
+        Ok(())
+    }
 }
 
 #[test]
@@ -119,3 +189,30 @@ fn test_merge_clocks() {
     vc.merge_remote(&[0, 1, 0, 0, 2]);
     assert_eq!(vc.clock.as_slice(), [2, 1, 0, 0, 2])
 }
+
+#[test]
+fn test_check_causality() {
+    let vc = VectorClock::from_parts(0, vec![2, 1]).unwrap();
+    assert_eq!(vc.check_causality(1, &[2, 2]), Ok(()));
+    assert_eq!(
+        vc.check_causality(1, &[2, 1]),
+        Err(Error::DuplicateOperation {
+            sent_by: 1,
+            sequence: 1
+        })
+    );
+    assert_eq!(
+        vc.check_causality(1, &[3, 2]),
+        Err(Error::CausalityGap {
+            site: 0,
+            received: 2,
+            required: 3
+        })
+    );
+    assert_eq!(
+        vc.check_causality(2, &[0, 1]),
+        Err(Error::MalformedClock { site_id: 2, len: 2 })
+    );
+    assert!(VectorClock::from_parts(3, vec![0]).is_err());
+    assert!(VectorClock::from_parts(0, vec![u32::MAX, 1]).is_err());
+}
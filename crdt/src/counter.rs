@@ -0,0 +1,326 @@
+//! Counters that every site changes concurrently. Each site only changes its own entry, so the
+//! entries are indexed by site id like a [`VectorClock`] and replicas merge by taking the
+//! larger value of every entry.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    causal::{deliver, deliver_ready, CausalBuffer, CausalOperation, CausalReplica},
+    clocks::VectorClock,
+    data_structure::ApplyStatus,
+    Error,
+};
+
+/// Adds `amount` to the entry of the sending site.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CounterOperation<N> {
+    pub sent_by: usize,
+    pub op_clock: Vec<u32>,
+    pub amount: N,
+}
+
+impl<N> CausalOperation for CounterOperation<N> {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    fn first_clock(&self) -> Vec<u32> {
+        self.op_clock.clone()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GCounterSnapshot {
+    pub clock: Vec<u32>,
+    pub counts: Vec<u64>,
+}
+
+/// A counter that only grows.
+pub struct GCounter {
+    clock: VectorClock,
+    counts: Vec<u64>,
+    pending: CausalBuffer<CounterOperation<u64>>,
+}
+
+impl GCounter {
+    pub fn new(id: usize) -> GCounter {
+        GCounter {
+            clock: VectorClock::new(id),
+            counts: Vec::new(),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn from_snapshot(id: usize, snapshot: GCounterSnapshot) -> GCounter {
+        let mut clock = VectorClock::new(id);
+        clock.merge_remote(&snapshot.clock);
+        GCounter {
+            clock,
+            counts: snapshot.counts,
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> GCounterSnapshot {
+        GCounterSnapshot {
+            clock: self.clock.clock_values().to_vec(),
+            counts: self.counts.clone(),
+        }
+    }
+
+    /// Stays at `u64::MAX` once it got there, like every entry.
+    pub fn value(&self) -> u64 {
+        self.counts
+            .iter()
+            .fold(0, |sum, count| sum.saturating_add(*count))
+    }
+
+    pub fn local_increment(&mut self, amount: u64) -> CounterOperation<u64> {
+        self.clock.increase();
+        add(&mut self.counts, self.clock.id(), amount);
+        CounterOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            amount,
+        }
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[CounterOperation<u64>] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(CounterOperation<u64>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Every operation is counted once, no matter how often it is delivered.
+    pub fn apply_operation(
+        &mut self,
+        operation: &CounterOperation<u64>,
+    ) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+
+    /// Combines the state of another replica into this one.
+    pub fn merge(&mut self, other: &GCounter) -> Result<(), Error> {
+        raise_to(&mut self.counts, &other.counts);
+        self.clock.merge_remote(other.clock.clock_values());
+        self.pending.discard_applied(&self.clock);
+        deliver_ready(self)
+    }
+}
+
+impl CausalReplica for GCounter {
+    type Operation = CounterOperation<u64>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<CounterOperation<u64>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &CounterOperation<u64>) -> Result<(), Error> {
+        VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?;
+        add(&mut self.counts, operation.sent_by, operation.amount);
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PNCounterSnapshot {
+    pub clock: Vec<u32>,
+    pub increments: Vec<u64>,
+    pub decrements: Vec<u64>,
+}
+
+/// A counter that grows and shrinks. Increments and decrements are counted separately, so
+/// both only grow and merge like a [`GCounter`].
+pub struct PNCounter {
+    clock: VectorClock,
+    increments: Vec<u64>,
+    decrements: Vec<u64>,
+    pending: CausalBuffer<CounterOperation<i64>>,
+}
+
+impl PNCounter {
+    pub fn new(id: usize) -> PNCounter {
+        PNCounter {
+            clock: VectorClock::new(id),
+            increments: Vec::new(),
+            decrements: Vec::new(),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn from_snapshot(id: usize, snapshot: PNCounterSnapshot) -> PNCounter {
+        let mut clock = VectorClock::new(id);
+        clock.merge_remote(&snapshot.clock);
+        PNCounter {
+            clock,
+            increments: snapshot.increments,
+            decrements: snapshot.decrements,
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> PNCounterSnapshot {
+        PNCounterSnapshot {
+            clock: self.clock.clock_values().to_vec(),
+            increments: self.increments.clone(),
+            decrements: self.decrements.clone(),
+        }
+    }
+
+    /// Clamped to the range of `i64`.
+    pub fn value(&self) -> i64 {
+        let sum = |counts: &[u64]| counts.iter().map(|count| *count as i128).sum::<i128>();
+        let value = sum(&self.increments) - sum(&self.decrements);
+        value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /// Adds `amount` to the counter, negative amounts decrement it.
+    pub fn local_add(&mut self, amount: i64) -> CounterOperation<i64> {
+        self.clock.increase();
+        self.count(self.clock.id(), amount);
+        CounterOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            amount,
+        }
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[CounterOperation<i64>] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(CounterOperation<i64>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Every operation is counted once, no matter how often it is delivered.
+    pub fn apply_operation(
+        &mut self,
+        operation: &CounterOperation<i64>,
+    ) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+
+    /// Combines the state of another replica into this one.
+    pub fn merge(&mut self, other: &PNCounter) -> Result<(), Error> {
+        raise_to(&mut self.increments, &other.increments);
+        raise_to(&mut self.decrements, &other.decrements);
+        self.clock.merge_remote(other.clock.clock_values());
+        self.pending.discard_applied(&self.clock);
+        deliver_ready(self)
+    }
+
+    fn count(&mut self, site: usize, amount: i64) {
+        if amount < 0 {
+            add(&mut self.decrements, site, amount.unsigned_abs());
+        } else {
+            add(&mut self.increments, site, amount as u64);
+        }
+    }
+}
+
+impl CausalReplica for PNCounter {
+    type Operation = CounterOperation<i64>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<CounterOperation<i64>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &CounterOperation<i64>) -> Result<(), Error> {
+        VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?;
+        self.count(operation.sent_by, operation.amount);
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+fn add(counts: &mut Vec<u64>, site: usize, amount: u64) {
+    if counts.len() <= site {
+        counts.resize(site + 1, 0);
+    }
+    // entries stop at the maximum the same way at every site
+    counts[site] = counts[site].saturating_add(amount);
+}
+
+fn raise_to(counts: &mut Vec<u64>, values: &[u64]) {
+    if counts.len() < values.len() {
+        counts.resize(values.len(), 0);
+    }
+    for (count, value) in counts.iter_mut().zip(values) {
+        *count = (*count).max(*value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_g_counter() {
+        let mut views0 = GCounter::new(0);
+        let mut views1 = GCounter::new(1);
+        let mut views2 = GCounter::new(2);
+        let ops0 = [views0.local_increment(1), views0.local_increment(2)];
+        let op1 = views1.local_increment(4);
+
+        // delivered twice and out of order
+        for op in ops0.iter().rev().chain(&ops0) {
+            views1.apply_operation(op).unwrap();
+        }
+        views0.apply_operation(&op1).unwrap();
+        assert_eq!(views0.value(), 7);
+        assert_eq!(views1.value(), 7);
+
+        // operations contained in a merged replica aren't counted again
+        views2.merge(&views1).unwrap();
+        assert_eq!(
+            views2.apply_operation(&op1),
+            Ok(ApplyStatus::AlreadyApplied)
+        );
+        let op0 = views0.local_increment(8);
+        views2.apply_operation(&op0).unwrap();
+        assert_eq!(views2.value(), 15);
+    }
+
+    #[test]
+    fn test_pn_counter() {
+        let mut votes0 = PNCounter::new(0);
+        let mut votes1 = PNCounter::new(1);
+        let ops = [votes0.local_add(3), votes0.local_add(-1)];
+        votes1.local_add(-5);
+        votes1.apply_operation(&ops[1]).unwrap();
+        assert_eq!(votes1.value(), -5);
+
+        let json = serde_json::to_string(&votes0.snapshot()).unwrap();
+        let restored = PNCounter::from_snapshot(0, serde_json::from_str(&json).unwrap());
+        votes1.merge(&restored).unwrap();
+        assert!(votes1.pending_operations().is_empty());
+        votes0.merge(&votes1).unwrap();
+        assert_eq!(votes0.value(), -3);
+        assert_eq!(votes1.value(), -3);
+
+        // remote amounts can't overflow the counter
+        let mut large = PNCounter::new(2);
+        for _ in 0..3 {
+            votes0.apply_operation(&large.local_add(i64::MAX)).unwrap();
+        }
+        assert_eq!(votes0.value(), i64::MAX);
+    }
+}
@@ -0,0 +1,132 @@
+//! Cursors and selections that stay attached to the values around them while other sites
+//! insert and delete. They are created and read through [`SynchronizedSequence::cursor_at`]
+//! and [`SynchronizedSequence::cursor_index`], a sequence can have any number of them.
+//!
+//! [`SynchronizedSequence::cursor_at`]: crate::data_structure::SynchronizedSequence::cursor_at
+//! [`SynchronizedSequence::cursor_index`]: crate::data_structure::SynchronizedSequence::cursor_index
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clocks::S4Vector, marks::Anchor};
+
+/// The value a cursor sticks to. Values inserted right at a cursor with `Left` gravity end up
+/// behind it, with `Right` gravity in front of it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gravity {
+    Left,
+    Right,
+}
+
+/// A gap between two values. It keeps referring to the value it sticks to after that value
+/// was deleted, the cursor is then where the value used to be.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub anchor: Anchor,
+}
+
+impl Cursor {
+    /// The start of every sequence, in front of any value inserted there.
+    pub fn start() -> Cursor {
+        Cursor {
+            anchor: Anchor::After(S4Vector::root().to_array()),
+        }
+    }
+
+    pub fn gravity(&self) -> Gravity {
+        match self.anchor {
+            Anchor::After(_) => Gravity::Left,
+            Anchor::Before(_) | Anchor::End => Gravity::Right,
+        }
+    }
+
+    /// The position of the value the cursor sticks to, `None` at the end of the sequence.
+    pub fn position(&self) -> Option<S4Vector> {
+        self.anchor.position()
+    }
+}
+
+/// The values between `anchor`, where the selection started, and `head`, where it ends now.
+/// Both are equal for a plain cursor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Selection {
+    pub anchor: Cursor,
+    pub head: Cursor,
+}
+
+impl Selection {
+    pub fn collapsed(cursor: Cursor) -> Selection {
+        Selection {
+            anchor: cursor,
+            head: cursor,
+        }
+    }
+
+    pub fn is_collapsed(&self) -> bool {
+        self.anchor == self.head
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::SynchronizedText;
+
+    #[test]
+    fn test_gravity() {
+        let mut text0 = SynchronizedText::new(0);
+        let mut text1 = SynchronizedText::new(1);
+        text1
+            .apply_operation(&text0.insert_str_at(0, "abcd").unwrap())
+            .unwrap();
+        let left = text0.cursor_at(2, Gravity::Left).unwrap();
+        let right = text0.cursor_at(2, Gravity::Right).unwrap();
+        let end = text0.cursor_at(4, Gravity::Right).unwrap();
+        assert_eq!(right.gravity(), Gravity::Right);
+
+        // text typed right at the cursors ends up behind the left and in front of the right one
+        let op = text1.insert_str_at(2, "xy").unwrap();
+        text0.apply_operation(&op).unwrap();
+        assert_eq!(text0.cursor_index(&left), Some(2));
+        assert_eq!(text0.cursor_index(&right), Some(4));
+
+        // the cursors stay where their deleted values used to be
+        let op = text1.delete_range(0, 5).unwrap().unwrap();
+        text0.apply_operation(&op).unwrap();
+        assert_eq!(text0.get_text(), "d");
+        assert_eq!(text0.cursor_index(&left), Some(0));
+        assert_eq!(text0.cursor_index(&right), Some(0));
+        assert_eq!(text0.cursor_index(&end), Some(1));
+
+        // tombstones cursors stick to aren't collected
+        text0.add_peer(1);
+        text0.acknowledge(1, text1.get_clock().clock_values());
+        text0.collect_garbage_keeping(&[left]);
+        assert_eq!(text0.cursor_index(&left), Some(0));
+        assert_eq!(text0.cursor_index(&right), None);
+    }
+
+    #[test]
+    fn test_selection() {
+        let mut text0 = SynchronizedText::new(0);
+        let mut text1 = SynchronizedText::new(1);
+        text1
+            .apply_operation(&text0.insert_str_at(0, "hello").unwrap())
+            .unwrap();
+        // selected backwards from 4 to 1
+        let selection = text0.selection_at(4, 1).unwrap();
+        assert!(!selection.is_collapsed());
+
+        // text typed at either edge of the selection by others isn't selected
+        for (index, text) in [(4, "!"), (1, "?")] {
+            let op = text1.insert_str_at(index, text).unwrap();
+            text0.apply_operation(&op).unwrap();
+        }
+        assert_eq!(text0.get_text(), "h?ell!o");
+        assert_eq!(text0.selection_range(&selection), Some((2, 5)));
+        assert_eq!(text0.cursor_index(&selection.head), Some(2));
+
+        let caret = text0.selection_at(3, 3).unwrap();
+        assert!(caret.is_collapsed());
+        assert_eq!(caret.head.gravity(), Gravity::Left);
+    }
+}
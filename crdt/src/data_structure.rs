@@ -1,137 +1,916 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    causal::{deliver, deliver_ready, is_applied, CausalBuffer, CausalOperation, CausalReplica},
     clocks::{S4Vector, VectorClock},
-    rga::{SnapshotIter, RGA},
+    cursor::{Cursor, Gravity, Selection},
+    marks::{formatted_runs, Anchor, FormattedRun, Mark, MarkOperation},
+    rga::{NodeSnapshot, SnapshotIter, RGA},
+    Error,
 };
 
+// single values keep the field name of the text-only operations, so older messages still work
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct InsertOperation {
-    pub character: char,
+pub struct InsertOperation<T = char> {
+    #[serde(rename = "character", alias = "value")]
+    pub value: T,
     pub insert_after: [u32; 4],
     pub insert_position: [u32; 4],
 }
 
+/// Values inserted at once. They get consecutive positions starting with `insert_position`
+/// and each of them counts as an operation of the sending site.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InsertRunOperation<T = char> {
+    pub values: Vec<T>,
+    pub insert_after: [u32; 4],
+    pub insert_position: [u32; 4],
+}
+
+/// Replaces the value at `position`, the update with the largest timestamp wins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateOperation<T = char> {
+    #[serde(rename = "character", alias = "value")]
+    pub value: T,
+    pub position: [u32; 4],
+}
+
+/// Moves the value at `position` behind `insert_after`. The value gets a new position, the
+/// timestamp of the operation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MoveOperation {
+    pub position: [u32; 4],
+    pub insert_after: [u32; 4],
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum OperationData {
-    Insert(InsertOperation),
+pub enum OperationData<T = char> {
+    Insert(InsertOperation<T>),
     Delete([u32; 4]),
+    InsertRun(InsertRunOperation<T>),
+    /// Deletes all positions as one operation.
+    DeleteRange(Vec<[u32; 4]>),
+    Update(UpdateOperation<T>),
+    Move(MoveOperation),
+    AddMark(MarkOperation),
+    RemoveMark(MarkOperation),
+    /// Brings back the deleted values at all positions as one operation.
+    Restore(Vec<[u32; 4]>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Operation {
+pub struct Operation<T = char> {
     pub sent_by: usize,
     pub op_clock: Vec<u32>,
-    pub data: OperationData,
+    pub data: OperationData<T>,
+}
+
+impl<T> Operation<T> {
+    /// Number of operations of the sending site this operation stands for. `op_clock` is the
+    /// clock after the last of them.
+    pub fn span(&self) -> u32 {
+        match &self.data {
+            OperationData::InsertRun(run) => run.values.len().max(1) as u32,
+            _ => 1,
+        }
+    }
+}
+
+impl<T> CausalOperation for Operation<T> {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    // the clock the first of the spanned operations would have had
+    fn first_clock(&self) -> Vec<u32> {
+        let mut clock = self.op_clock.clone();
+        if let Some(value) = clock.get_mut(self.sent_by) {
+            *value = value.saturating_sub(self.span() - 1);
+        }
+        clock
+    }
 }
 
-pub struct SynchronizedText {
+/// Outcome of handing a remote operation to [`SynchronizedSequence::apply_operation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyStatus {
+    /// The operation was integrated into the text.
+    Applied,
+    /// The operation arrived before its causal dependencies and is kept until they are applied.
+    Buffered,
+    /// The operation was integrated before, delivering it again has no effect.
+    AlreadyApplied,
+}
+
+/// Full state of a [`SynchronizedSequence`], enough to continue receiving operations from the
+/// point the snapshot was taken.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SequenceSnapshot<T = char> {
+    pub clock: Vec<u32>,
+    pub nodes: Vec<NodeSnapshot<T>>,
+    #[serde(default)]
+    pub marks: Vec<Mark>,
+}
+
+pub type TextSnapshot = SequenceSnapshot<char>;
+
+/// A list of values that several sites edit concurrently by exchanging operations.
+pub struct SynchronizedSequence<T> {
     clock: VectorClock,
-    rga: RGA<char>,
+    rga: RGA<T>,
+    pending: CausalBuffer<Operation<T>>,
+    acknowledged: HashMap<usize, Vec<u32>>,
+    // nothing is stable before the peers are declared, undeclared sites may not have seen it
+    peers_declared: bool,
+    log: Vec<Operation<T>>,
+    // operations covered by this clock may be missing from the log
+    history_floor: Vec<u32>,
+    marks: Vec<Mark>,
 }
 
-impl SynchronizedText {
-    pub fn new(id: usize) -> SynchronizedText {
-        SynchronizedText {
+pub type SynchronizedText = SynchronizedSequence<char>;
+
+impl<T: Clone> SynchronizedSequence<T> {
+    pub fn new(id: usize) -> SynchronizedSequence<T> {
+        SynchronizedSequence {
             clock: VectorClock::new(id),
             rga: RGA::new(),
+            pending: CausalBuffer::new(),
+            acknowledged: HashMap::new(),
+            peers_declared: false,
+            log: Vec::new(),
+            history_floor: Vec::new(),
+            marks: Vec::new(),
         }
     }
-    pub fn get_text(&self) -> String {
-        self.rga
-            .iter()
-            .filter(|(_, c)| c.is_some())
-            .map(|(_, c)| c.unwrap())
-            .collect()
+    /// Loads a snapshot taken by any site. `id` is the site of the new replica, pass the id
+    /// of the site that took the snapshot to restore a saved document.
+    pub fn from_snapshot(
+        id: usize,
+        snapshot: SequenceSnapshot<T>,
+    ) -> Result<SynchronizedSequence<T>, Error> {
+        let mut clock = VectorClock::new(id);
+        clock.merge_remote(&snapshot.clock);
+        Ok(SynchronizedSequence {
+            clock,
+            rga: RGA::from_snapshot(snapshot.nodes)?,
+            pending: CausalBuffer::new(),
+            acknowledged: HashMap::new(),
+            peers_declared: false,
+            log: Vec::new(),
+            history_floor: snapshot.clock,
+            marks: snapshot.marks,
+        })
+    }
+
+    pub fn snapshot(&self) -> SequenceSnapshot<T> {
+        SequenceSnapshot {
+            clock: self.clock.clock_values().to_vec(),
+            nodes: self.rga.snapshot(),
+            marks: self.marks.clone(),
+        }
+    }
+
+    /// The values that are not deleted, in order.
+    pub fn values(&self) -> Vec<T> {
+        self.rga.iter().filter_map(|(_, value)| value).collect()
+    }
+
+    /// The value at `position`, `None` if it is deleted.
+    pub fn get(&self, position: S4Vector) -> Option<T> {
+        self.rga.get(self.rga.resolve(position)?).cloned()
+    }
+
+    /// Whether `position` is known, deleted values stay known until they are collected.
+    pub fn contains(&self, position: &S4Vector) -> bool {
+        self.rga.contains(position)
     }
 
     pub fn get_positions(&self) -> Vec<S4Vector> {
         self.rga.iter().map(|(p, _)| p).collect()
     }
 
-    pub fn local_insert(&mut self, insert_after: S4Vector, character: char) -> Operation {
+    pub fn len(&self) -> usize {
+        self.rga.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rga.is_empty()
+    }
+
+    /// Indices count visible values like cursor offsets: index 0 is the root, index `i` is the
+    /// `i`-th visible value, so inserting after `position_at(i)` inserts at `i`.
+    pub fn position_at(&self, index: usize) -> Option<S4Vector> {
+        self.rga.position_at(index)
+    }
+
+    /// Inverse of [`SynchronizedSequence::position_at`]. Deleted positions map to the index of
+    /// the closest visible value before them.
+    pub fn index_of(&self, position: S4Vector) -> Option<usize> {
+        self.rga.index_of(position)
+    }
+
+    /// A cursor in front of the value at `index`, 0 is the start. It sticks to the value on
+    /// its left or right depending on `gravity`.
+    pub fn cursor_at(&self, index: usize, gravity: Gravity) -> Result<Cursor, Error> {
+        let out_of_bounds = Error::IndexOutOfBounds {
+            index,
+            len: self.len(),
+        };
+        let anchor = match gravity {
+            Gravity::Left => {
+                Anchor::After(self.position_at(index).ok_or(out_of_bounds)?.to_array())
+            }
+            Gravity::Right if index == self.len() => Anchor::End,
+            Gravity::Right => {
+                Anchor::Before(self.position_at(index + 1).ok_or(out_of_bounds)?.to_array())
+            }
+        };
+        Ok(Cursor { anchor })
+    }
+
+    /// The index `cursor` is at now, `None` if the value it sticks to was collected.
+    pub fn cursor_index(&self, cursor: &Cursor) -> Option<usize> {
+        let Some(position) = cursor.position() else {
+            return Some(self.len());
+        };
+        let position = self.rga.resolve(position)?;
+        let index = self.rga.index_of(position)?;
+        match cursor.anchor {
+            // a visible value behind the cursor is counted by its index
+            Anchor::Before(_) if self.rga.get(position).is_some() => Some(index - 1),
+            _ => Some(index),
+        }
+    }
+
+    /// Selects the values between the indices `anchor` and `head`. Values others insert at
+    /// either edge of the selection end up outside of it.
+    pub fn selection_at(&self, anchor: usize, head: usize) -> Result<Selection, Error> {
+        // the start sticks to the first selected value, the end and a plain cursor to the
+        // value in front of them
+        let gravity = |index: usize, other: usize| {
+            if index < other {
+                Gravity::Right
+            } else {
+                Gravity::Left
+            }
+        };
+        Ok(Selection {
+            anchor: self.cursor_at(anchor, gravity(anchor, head))?,
+            head: self.cursor_at(head, gravity(head, anchor))?,
+        })
+    }
+
+    /// Start and end index of `selection`, `None` if it lost one of its values.
+    pub fn selection_range(&self, selection: &Selection) -> Option<(usize, usize)> {
+        let anchor = self.cursor_index(&selection.anchor)?;
+        let head = self.cursor_index(&selection.head)?;
+        Some((anchor.min(head), anchor.max(head)))
+    }
+
+    pub fn insert_at(
+        &mut self,
+        index: usize,
+        values: Vec<T>,
+    ) -> Result<Option<Operation<T>>, Error> {
+        let insert_after = self.position_at(index).ok_or(Error::IndexOutOfBounds {
+            index,
+            len: self.len(),
+        })?;
+        self.local_insert_run(insert_after, values)
+    }
+
+    pub fn delete_range(
+        &mut self,
+        index: usize,
+        len: usize,
+    ) -> Result<Option<Operation<T>>, Error> {
+        let Some(end) = index.checked_add(len).filter(|end| *end <= self.len()) else {
+            return Err(Error::IndexOutOfBounds {
+                index: index.saturating_add(len),
+                len: self.len(),
+            });
+        };
+        let positions: Vec<S4Vector> = (index + 1..=end)
+            .filter_map(|idx| self.rga.position_at(idx))
+            .collect();
+        self.local_delete_range(&positions)
+    }
+
+    pub fn local_insert(
+        &mut self,
+        insert_after: S4Vector,
+        value: T,
+    ) -> Result<Operation<T>, Error> {
+        let insert_after = self.visible_reference(insert_after)?;
         self.clock.increase();
         self.rga
-            .insert(insert_after, self.clock.to_s4vector(), character);
-        Operation {
+            .insert(insert_after, self.clock.to_s4vector(), value.clone())?;
+        Ok(self.record(Operation {
             sent_by: self.clock.id(),
             op_clock: self.clock.clock_values().to_vec(),
             data: OperationData::Insert(InsertOperation {
-                character,
+                value,
                 insert_after: insert_after.to_array(),
                 insert_position: self.clock.to_s4vector().to_array(),
             }),
+        }))
+    }
+
+    /// Inserts `values` behind `insert_after` with a single operation, `None` if there are none.
+    pub fn local_insert_run(
+        &mut self,
+        insert_after: S4Vector,
+        mut values: Vec<T>,
+    ) -> Result<Option<Operation<T>>, Error> {
+        if values.len() <= 1 {
+            return values
+                .pop()
+                .map(|value| self.local_insert(insert_after, value))
+                .transpose();
+        }
+        let insert_after = self.visible_reference(insert_after)?;
+        self.clock.increase();
+        let insert_position = self.clock.to_s4vector();
+        for _ in 1..values.len() {
+            self.clock.increase();
         }
+        self.rga
+            .insert_run(insert_after, insert_position, values.clone())?;
+        Ok(Some(self.record(Operation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data: OperationData::InsertRun(InsertRunOperation {
+                values,
+                insert_after: insert_after.to_array(),
+                insert_position: insert_position.to_array(),
+            }),
+        })))
+    }
+
+    // Deleted positions are replaced by the closest visible one in front of them. This looks
+    // the same to the user but never references a tombstone that might be collected.
+    fn visible_reference(&self, position: S4Vector) -> Result<S4Vector, Error> {
+        self.rga
+            .index_of(position)
+            .and_then(|index| self.rga.position_at(index))
+            .ok_or(Error::UnknownPosition(position))
     }
 
     pub fn remote_insert(
         &mut self,
         operation_position: S4Vector,
         insert_after: S4Vector,
-        character: char,
-    ) {
-        self.rga.insert(insert_after, operation_position, character);
+        value: T,
+    ) -> Result<(), Error> {
+        self.rga.insert(insert_after, operation_position, value)
+    }
+
+    /// Inserts `values` at consecutive positions starting with `first_position`, all of them
+    /// or none.
+    pub fn remote_insert_run(
+        &mut self,
+        first_position: S4Vector,
+        insert_after: S4Vector,
+        values: Vec<T>,
+    ) -> Result<(), Error> {
+        self.rga.insert_run(insert_after, first_position, values)
     }
 
-    pub fn local_delete(&mut self, delete_position: S4Vector) -> Operation {
+    pub fn local_delete(&mut self, delete_position: S4Vector) -> Result<Operation<T>, Error> {
+        if !self.rga.contains(&delete_position) {
+            return Err(Error::UnknownPosition(delete_position));
+        }
+        // operations refer to values by the position they were inserted at, the slots moves
+        // put them in may be collected
+        let delete_position = self.rga.element_of(delete_position);
         self.clock.increase();
-        self.rga.delete(delete_position, self.clock.to_s4vector());
-        Operation {
+        self.rga.delete(delete_position, self.clock.to_s4vector())?;
+        Ok(self.record(Operation {
             sent_by: self.clock.id(),
             op_clock: self.clock.clock_values().to_vec(),
             data: OperationData::Delete(delete_position.to_array()),
+        }))
+    }
+
+    /// Deletes all `positions` with a single operation, `None` if there are none.
+    pub fn local_delete_range(
+        &mut self,
+        positions: &[S4Vector],
+    ) -> Result<Option<Operation<T>>, Error> {
+        match positions {
+            [] => return Ok(None),
+            [position] => return self.local_delete(*position).map(Some),
+            _ => {}
+        }
+        if let Some(missing) = positions.iter().find(|pos| !self.rga.contains(pos)) {
+            return Err(Error::UnknownPosition(*missing));
+        }
+        let positions = self.elements(positions);
+        self.clock.increase();
+        self.rga
+            .delete_range(&positions, self.clock.to_s4vector())?;
+        Ok(Some(self.record(Operation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data: OperationData::DeleteRange(positions.iter().map(S4Vector::to_array).collect()),
+        })))
+    }
+
+    /// Brings back the deleted values at `positions` with a single operation, `None` if there
+    /// are none. The values keep their positions, so references to them stay valid.
+    ///
+    /// Values whose deletion is stable can't be restored, other sites may have collected them.
+    pub fn local_restore(&mut self, positions: &[S4Vector]) -> Result<Option<Operation<T>>, Error> {
+        if positions.is_empty() {
+            return Ok(None);
+        }
+        self.check_restorable(positions)?;
+        let positions = self.elements(positions);
+        self.clock.increase();
+        self.rga
+            .restore_range(&positions, self.clock.to_s4vector())?;
+        Ok(Some(self.record(Operation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data: OperationData::Restore(positions.iter().map(S4Vector::to_array).collect()),
+        })))
+    }
+
+    fn elements(&self, positions: &[S4Vector]) -> Vec<S4Vector> {
+        positions
+            .iter()
+            .map(|pos| self.rga.element_of(*pos))
+            .collect()
+    }
+
+    pub(crate) fn check_restorable(&self, positions: &[S4Vector]) -> Result<(), Error> {
+        if let Some(missing) = positions.iter().find(|pos| !self.rga.contains(pos)) {
+            return Err(Error::UnknownPosition(*missing));
+        }
+        let stable = self.stable_clock();
+        let is_stable = |ts: S4Vector| stable.get(ts.sid as usize).is_some_and(|v| *v >= ts.seq);
+        match positions
+            .iter()
+            .find(|pos| self.rga.deleted_at(**pos).is_some_and(is_stable))
+        {
+            Some(collectable) => Err(Error::StableDeletion(*collectable)),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the value at `position`. Values that are deleted, here or concurrently by
+    /// another site, stay deleted.
+    pub fn local_update(&mut self, position: S4Vector, value: T) -> Result<Operation<T>, Error> {
+        if !self.rga.contains(&position) {
+            return Err(Error::UnknownPosition(position));
+        }
+        let position = self.rga.element_of(position);
+        self.clock.increase();
+        self.rga
+            .update(position, value.clone(), self.clock.to_s4vector())?;
+        Ok(self.record(Operation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data: OperationData::Update(UpdateOperation {
+                value,
+                position: position.to_array(),
+            }),
+        }))
+    }
+
+    pub fn remote_update(
+        &mut self,
+        operation_ts: S4Vector,
+        position: S4Vector,
+        value: T,
+    ) -> Result<(), Error> {
+        self.rga.update(position, value, operation_ts)
+    }
+
+    /// Moves the value at `position` behind `insert_after`. If several sites move the same value
+    /// concurrently, it ends up where the move with the largest timestamp put it.
+    pub fn local_move(
+        &mut self,
+        position: S4Vector,
+        insert_after: S4Vector,
+    ) -> Result<Operation<T>, Error> {
+        if !self.rga.contains(&position) {
+            return Err(Error::UnknownPosition(position));
+        }
+        let position = self.rga.element_of(position);
+        let insert_after = self.visible_reference(insert_after)?;
+        self.clock.increase();
+        self.rga
+            .move_to(position, insert_after, self.clock.to_s4vector())?;
+        Ok(self.record(Operation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data: OperationData::Move(MoveOperation {
+                position: position.to_array(),
+                insert_after: insert_after.to_array(),
+            }),
+        }))
+    }
+
+    pub fn remote_move(
+        &mut self,
+        operation_ts: S4Vector,
+        position: S4Vector,
+        insert_after: S4Vector,
+    ) -> Result<(), Error> {
+        self.rga.move_to(position, insert_after, operation_ts)
+    }
+
+    /// Formats the `len` values starting at `index` with `name`. Marks that `expand` also
+    /// cover values inserted directly behind them later, like bold text but unlike links.
+    pub fn add_mark(
+        &mut self,
+        index: usize,
+        len: usize,
+        name: &str,
+        value: Option<String>,
+        expand: bool,
+    ) -> Result<Option<Operation<T>>, Error> {
+        let Some(mark) = self.mark_range(index, len, name, value, expand)? else {
+            return Ok(None);
+        };
+        self.local_mark(mark, false).map(Some)
+    }
+
+    /// Removes the formatting `name` from the `len` values starting at `index`.
+    pub fn remove_mark(
+        &mut self,
+        index: usize,
+        len: usize,
+        name: &str,
+        expand: bool,
+    ) -> Result<Option<Operation<T>>, Error> {
+        let Some(mark) = self.mark_range(index, len, name, None, expand)? else {
+            return Ok(None);
+        };
+        self.local_mark(mark, true).map(Some)
+    }
+
+    pub fn marks(&self) -> &[Mark] {
+        &self.marks
+    }
+
+    fn mark_range(
+        &self,
+        index: usize,
+        len: usize,
+        name: &str,
+        value: Option<String>,
+        expand: bool,
+    ) -> Result<Option<MarkOperation>, Error> {
+        let Some(end) = index.checked_add(len).filter(|end| *end <= self.len()) else {
+            return Err(Error::IndexOutOfBounds {
+                index: index.saturating_add(len),
+                len: self.len(),
+            });
+        };
+        if len == 0 {
+            return Ok(None);
+        }
+        let first = self.rga.position_at(index + 1).unwrap();
+        let last = self.rga.position_at(end).unwrap();
+        let end = if expand {
+            end.checked_add(1)
+                .and_then(|next| self.rga.position_at(next))
+                .map_or(Anchor::End, |next| Anchor::Before(next.to_array()))
+        } else {
+            Anchor::After(last.to_array())
+        };
+        Ok(Some(MarkOperation {
+            name: name.to_owned(),
+            value,
+            start: Anchor::Before(first.to_array()),
+            end,
+        }))
+    }
+
+    fn local_mark(&mut self, mark: MarkOperation, removed: bool) -> Result<Operation<T>, Error> {
+        self.clock.increase();
+        self.apply_mark(self.clock.to_s4vector(), mark.clone(), removed)?;
+        Ok(self.record(Operation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data: if removed {
+                OperationData::RemoveMark(mark)
+            } else {
+                OperationData::AddMark(mark)
+            },
+        }))
+    }
+
+    fn apply_mark(
+        &mut self,
+        operation_ts: S4Vector,
+        mark: MarkOperation,
+        removed: bool,
+    ) -> Result<(), Error> {
+        for position in [mark.start.position(), mark.end.position()]
+            .into_iter()
+            .flatten()
+        {
+            if !self.rga.contains(&position) {
+                return Err(Error::UnknownPosition(position));
+            }
+        }
+        self.marks.push(Mark {
+            timestamp: operation_ts.to_array(),
+            removed,
+            mark,
+        });
+        Ok(())
+    }
+
+    fn record(&mut self, operation: Operation<T>) -> Operation<T> {
+        self.log.push(operation.clone());
+        operation
+    }
+
+    /// All operations applied here that the replica with `clock` hasn't seen, in an order that
+    /// can be applied directly.
+    pub fn operations_since(&self, clock: &VectorClock) -> Result<Vec<Operation<T>>, Error> {
+        let complete = self
+            .history_floor
+            .iter()
+            .enumerate()
+            .all(|(site, value)| *value <= clock.clock_value(site));
+        if !complete {
+            return Err(Error::MissingHistory);
         }
+        Ok(self
+            .log
+            .iter()
+            .filter(|op| op.op_clock[op.sent_by] > clock.clock_value(op.sent_by))
+            .cloned()
+            .collect())
+    }
+
+    pub fn remote_delete(
+        &mut self,
+        operation_ts: S4Vector,
+        delete_position: S4Vector,
+    ) -> Result<(), Error> {
+        self.rga.delete(delete_position, operation_ts)
     }
 
-    pub fn remote_delete(&mut self, operation_ts: S4Vector, delete_position: S4Vector) {
-        self.rga.delete(delete_position, operation_ts);
+    pub fn remote_delete_range(
+        &mut self,
+        operation_ts: S4Vector,
+        positions: &[S4Vector],
+    ) -> Result<(), Error> {
+        self.rga.delete_range(positions, operation_ts)
     }
 
     pub fn is_ready_to_receive(&self, sent_by: usize, sent_clock_values: &[u32]) -> bool {
-        for idx in 0..sent_clock_values.len() {
-            // check if any of the clock values is larger than the arriving one
-            if sent_clock_values[idx] > self.clock.clock_value(idx) && idx != sent_by {
-                return false;
+        self.clock
+            .check_causality(sent_by, sent_clock_values)
+            .is_ok()
+    }
+
+    /// Records that `site_id` has seen all operations covered by `clock_values`.
+    ///
+    /// Acknowledgements must not overtake the operations of the acknowledging site, so they are
+    /// ignored if they claim more operations of `site_id` than were applied here.
+    pub fn acknowledge(&mut self, site_id: usize, clock_values: &[u32]) -> bool {
+        if site_id == self.clock.id()
+            || clock_values.get(site_id).copied().unwrap_or(0) > self.clock.clock_value(site_id)
+        {
+            return false;
+        }
+        raise_to(self.acknowledged.entry(site_id).or_default(), clock_values);
+        true
+    }
+
+    /// Registers a site that didn't send anything yet. Garbage is only collected once every
+    /// known site acknowledged it, so all peers have to be added before collecting garbage.
+    /// Until a peer is added nothing is collected, a site working alone adds its own id.
+    pub fn add_peer(&mut self, site_id: usize) {
+        self.peers_declared = true;
+        if site_id != self.clock.id() {
+            self.acknowledged.entry(site_id).or_default();
+        }
+    }
+
+    /// Minimum of the own clock and the acknowledged clocks of all known sites. Operations
+    /// covered by it were applied by every site. Empty until the peers were added.
+    pub fn stable_clock(&self) -> Vec<u32> {
+        if !self.peers_declared {
+            return Vec::new();
+        }
+        let sites = self
+            .acknowledged
+            .iter()
+            .map(|(id, clock)| clock.len().max(id + 1))
+            .chain([self.clock.clock_values().len()])
+            .max()
+            .unwrap_or(0);
+        let mut stable = self.clock.clock_values().to_vec();
+        stable.resize(sites, 0);
+        for site in (0..sites).filter(|site| *site != self.clock.id()) {
+            let acknowledged = self
+                .acknowledged
+                .get(&site)
+                .map_or(&[][..], |c| c.as_slice());
+            for (idx, value) in stable.iter_mut().enumerate() {
+                *value = (*value).min(acknowledged.get(idx).copied().unwrap_or(0));
             }
         }
+        stable
+    }
 
-        let sent_clock_value: u32 = *sent_clock_values
-            .get(sent_by)
-            .expect("The sender must have the value for it's own id");
-        sent_clock_value == self.clock.clock_value(sent_by) + 1
+    /// Removes deleted values that every site has seen deleted, returns how many were removed.
+    /// Operations every site has applied are dropped from the log as well.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.collect_garbage_keeping(&[])
+    }
+
+    /// Like [`SynchronizedSequence::collect_garbage`], but keeps the deleted values `cursors`
+    /// stick to, so they stay where the values used to be.
+    pub fn collect_garbage_keeping(&mut self, cursors: &[Cursor]) -> usize {
+        let stable = self.stable_clock();
+        self.log
+            .retain(|op| op.op_clock[op.sent_by] > stable.get(op.sent_by).copied().unwrap_or(0));
+        raise_to(&mut self.history_floor, &stable);
+        // marks and cursors keep the positions they are anchored at
+        let anchors: HashSet<S4Vector> = self
+            .marks
+            .iter()
+            .flat_map(|mark| [mark.mark.start.position(), mark.mark.end.position()])
+            .chain(cursors.iter().map(Cursor::position))
+            .flatten()
+            .collect();
+        self.rga
+            .purge_tombstones_except(&stable, |pos| anchors.contains(&pos))
+    }
+
+    /// Combines the state of another replica into this one. Afterwards this replica contains
+    /// all operations either of them had applied, as if they had been exchanged.
+    pub fn merge(&mut self, other: &SynchronizedSequence<T>) -> Result<(), Error> {
+        self.rga.merge(&other.rga, self.clock.clock_values())?;
+        let known: HashSet<[u32; 4]> = self.marks.iter().map(|mark| mark.timestamp).collect();
+        self.marks.extend(
+            other
+                .marks
+                .iter()
+                .filter(|mark| !known.contains(&mark.timestamp))
+                .cloned(),
+        );
+        self.clock.merge_remote(other.clock.clock_values());
+        // the merged operations never pass through the log
+        raise_to(&mut self.history_floor, other.clock.clock_values());
+        self.acknowledge(other.clock.id(), other.clock.clock_values());
+
+        self.pending.discard_applied(&self.clock);
+        deliver_ready(self)
     }
 
     pub fn get_clock(&self) -> &VectorClock {
         &self.clock
     }
 
-    pub fn iter(&self) -> SnapshotIter<'_, char> {
+    pub fn iter(&self) -> SnapshotIter<'_, T> {
         self.rga.iter()
     }
 
-    pub fn apply_operation(&mut self, operation: &Operation) -> Result<(), String> {
-        let clock = VectorClock::from_parts(operation.sent_by, operation.op_clock.clone());
-        if !self.is_ready_to_receive(operation.sent_by, &operation.op_clock) {
-            // Normally we would enqueue this operation and wait until the previous values would arrive
-            return Err("Not ready to receive this values".into());
-        }
+    pub fn pending_operations(&self) -> &[Operation<T>] {
+        self.pending.pending()
+    }
 
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(Operation<T>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Operations that are not causally ready yet are buffered and applied as soon as the
+    /// operations they depend on arrive.
+    pub fn apply_operation(&mut self, operation: &Operation<T>) -> Result<ApplyStatus, Error> {
+        check_run(operation)?;
+        deliver(self, operation)
+    }
+
+    pub fn is_already_applied(&self, operation: &Operation<T>) -> bool {
+        is_applied(&self.clock, operation)
+    }
+}
+
+impl<T: Clone> CausalReplica for SynchronizedSequence<T> {
+    type Operation = Operation<T>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<Operation<T>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &Operation<T>) -> Result<(), Error> {
+        let clock = VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?;
         match &operation.data {
             OperationData::Insert(data) => self.remote_insert(
                 data.insert_position.into(),
                 data.insert_after.into(),
-                data.character,
+                data.value.clone(),
             ),
-            OperationData::Delete(data) => {
-                self.remote_delete(clock.to_s4vector(), data.clone().into())
+            OperationData::Delete(data) => self.remote_delete(clock.to_s4vector(), (*data).into()),
+            OperationData::InsertRun(data) => self.rga.insert_run(
+                data.insert_after.into(),
+                data.insert_position.into(),
+                data.values.clone(),
+            ),
+            OperationData::DeleteRange(data) => {
+                let positions: Vec<S4Vector> = data.iter().map(|pos| (*pos).into()).collect();
+                self.rga.delete_range(&positions, clock.to_s4vector())
             }
-        };
+            OperationData::Update(data) => self.remote_update(
+                clock.to_s4vector(),
+                data.position.into(),
+                data.value.clone(),
+            ),
+            OperationData::Move(data) => self.remote_move(
+                clock.to_s4vector(),
+                data.position.into(),
+                data.insert_after.into(),
+            ),
+            OperationData::AddMark(data) => {
+                self.apply_mark(clock.to_s4vector(), data.clone(), false)
+            }
+            OperationData::RemoveMark(data) => {
+                self.apply_mark(clock.to_s4vector(), data.clone(), true)
+            }
+            OperationData::Restore(data) => {
+                let positions: Vec<S4Vector> = data.iter().map(|pos| (*pos).into()).collect();
+                self.rga.restore_range(&positions, clock.to_s4vector())
+            }
+        }?;
         self.clock.merge_remote(&operation.op_clock);
-
+        self.acknowledge(operation.sent_by, &operation.op_clock);
+        self.log.push(operation.clone());
         Ok(())
     }
 }
 
+impl SynchronizedText {
+    pub fn get_text(&self) -> String {
+        self.rga.iter().filter_map(|(_, c)| c).collect()
+    }
+
+    /// The text split into runs of characters with the same formatting.
+    pub fn formatted_runs(&self) -> Vec<FormattedRun> {
+        formatted_runs(&self.rga, &self.marks)
+    }
+
+    /// Inserts `text` at `index` with a single operation, an empty `text` is rejected.
+    pub fn insert_str_at(&mut self, index: usize, text: &str) -> Result<Operation, Error> {
+        self.insert_at(index, text.chars().collect())?
+            .ok_or(Error::EmptyRun)
+    }
+
+    /// Inserts `text` behind `insert_after` with a single operation, `None` if `text` is empty.
+    pub fn local_insert_str(
+        &mut self,
+        insert_after: S4Vector,
+        text: &str,
+    ) -> Result<Option<Operation>, Error> {
+        self.local_insert_run(insert_after, text.chars().collect())
+    }
+}
+
+// runs of remote sites have values and all their positions fit in a clock
+fn check_run<T>(operation: &Operation<T>) -> Result<(), Error> {
+    let OperationData::InsertRun(run) = &operation.data else {
+        return Ok(());
+    };
+    let last = run.values.len().checked_sub(1).ok_or(Error::EmptyRun)?;
+    let first = S4Vector::from(run.insert_position);
+    match u32::try_from(last)
+        .ok()
+        .and_then(|last| first.checked_run_position(last))
+    {
+        Some(_) => Ok(()),
+        None => Err(Error::MalformedClock {
+            site_id: operation.sent_by,
+            len: operation.op_clock.len(),
+        }),
+    }
+}
+
+fn raise_to(clock: &mut Vec<u32>, values: &[u32]) {
+    if clock.len() < values.len() {
+        clock.resize(values.len(), 0);
+    }
+    for (value, new_value) in clock.iter_mut().zip(values) {
+        *value = (*value).max(*new_value);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,12 +925,12 @@ mod tests {
         let mut text2_ops = vec![];
         let mut clk = S4Vector::root();
         for c in text1.chars() {
-            text1_ops.push(sync1.local_insert(clk, c));
+            text1_ops.push(sync1.local_insert(clk, c).unwrap());
             clk = sync1.clock.to_s4vector();
         }
         clk = S4Vector::root();
         for c in text2.chars() {
-            text2_ops.push(sync2.local_insert(clk, c));
+            text2_ops.push(sync2.local_insert(clk, c).unwrap());
             clk = sync2.clock.to_s4vector();
         }
 
@@ -178,7 +957,7 @@ mod tests {
                 sent_by: 5,
                 op_clock: [0, 0, 0, 0, 0, 1].to_vec(),
                 data: Insert(InsertOperation {
-                    character: 'q',
+                    value: 'q',
                     insert_after: [0, 0, 0, 0],
                     insert_position: [0, 5, 1, 1],
                 }),
@@ -187,7 +966,7 @@ mod tests {
                 sent_by: 1,
                 op_clock: [0, 1].to_vec(),
                 data: Insert(InsertOperation {
-                    character: 'E',
+                    value: 'E',
                     insert_after: [0, 0, 0, 0],
                     insert_position: [0, 1, 3, 3],
                 }),
@@ -207,4 +986,606 @@ mod tests {
         }
         assert_eq!(t1.get_text(), t2.get_text());
     }
+
+    #[test]
+    fn concurrent_moves() {
+        use OperationData::*;
+        let insert = Operation {
+            sent_by: 0,
+            op_clock: [3].to_vec(),
+            data: InsertRun(InsertRunOperation {
+                values: vec!['a', 'b', 'c'],
+                insert_after: [0, 0, 0, 0],
+                insert_position: [0, 0, 1, 1],
+            }),
+        };
+        let operations = [
+            Operation {
+                sent_by: 1,
+                op_clock: [3, 1].to_vec(),
+                data: Move(MoveOperation {
+                    position: [0, 0, 1, 1],
+                    insert_after: [0, 0, 3, 3],
+                }),
+            },
+            Operation {
+                sent_by: 2,
+                op_clock: [3, 0, 1].to_vec(),
+                data: Move(MoveOperation {
+                    position: [0, 0, 1, 1],
+                    insert_after: [0, 0, 2, 2],
+                }),
+            },
+            Operation {
+                sent_by: 1,
+                op_clock: [3, 2].to_vec(),
+                data: Delete([0, 0, 3, 3]),
+            },
+            // site 2 only knows its own move but addresses 'a' by its original position
+            Operation {
+                sent_by: 2,
+                op_clock: [3, 0, 2].to_vec(),
+                data: Update(UpdateOperation {
+                    value: 'A',
+                    position: [0, 0, 1, 1],
+                }),
+            },
+        ];
+
+        let mut texts = Vec::new();
+        for first in 0..operations.len() {
+            for second in 0..operations.len() {
+                let mut text = SynchronizedText::new(3);
+                text.apply_operation(&insert).unwrap();
+                let order = [first, second].into_iter().chain(0..operations.len());
+                for idx in order {
+                    text.apply_operation(&operations[idx]).unwrap();
+                }
+                texts.push(text);
+            }
+        }
+        for text in &texts {
+            assert_eq!(text.get_text(), "bA");
+            assert_eq!(text.snapshot().nodes, texts[0].snapshot().nodes);
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_delivery() {
+        let mut sync1 = SynchronizedText::new(0);
+        let mut sync2 = SynchronizedText::new(1);
+        let mut ops = vec![];
+        let mut clk = S4Vector::root();
+        for c in "abc".chars() {
+            ops.push(sync1.local_insert(clk, c).unwrap());
+            clk = sync1.clock.to_s4vector();
+        }
+        ops.push(sync1.local_delete(clk).unwrap());
+
+        for op in ops[1..].iter().rev() {
+            assert_eq!(sync2.apply_operation(op), Ok(ApplyStatus::Buffered));
+        }
+        assert_eq!(sync2.get_text(), "");
+        assert_eq!(sync2.pending_operations().len(), 3);
+
+        assert_eq!(sync2.apply_operation(&ops[0]), Ok(ApplyStatus::Applied));
+        assert!(sync2.pending_operations().is_empty());
+        assert_eq!(sync2.get_text(), "ab");
+        assert_eq!(sync2.get_text(), sync1.get_text());
+    }
+
+    #[test]
+    fn test_duplicate_delivery() {
+        let mut sync1 = SynchronizedText::new(0);
+        let mut sync2 = SynchronizedText::new(1);
+        let insert_a = sync1.local_insert(S4Vector::root(), 'a').unwrap();
+        let a = sync1.clock.to_s4vector();
+        let insert_b = sync1.local_insert(a, 'b').unwrap();
+        let delete_a = sync1.local_delete(a).unwrap();
+
+        assert_eq!(sync2.apply_operation(&insert_b), Ok(ApplyStatus::Buffered));
+        assert_eq!(sync2.apply_operation(&insert_b), Ok(ApplyStatus::Buffered));
+        assert_eq!(sync2.pending_operations().len(), 1);
+
+        assert_eq!(sync2.apply_operation(&insert_a), Ok(ApplyStatus::Applied));
+        assert_eq!(sync2.apply_operation(&delete_a), Ok(ApplyStatus::Applied));
+        for op in [&insert_a, &insert_b, &delete_a] {
+            assert_eq!(sync2.apply_operation(op), Ok(ApplyStatus::AlreadyApplied));
+        }
+        assert!(sync2.pending_operations().is_empty());
+        assert_eq!(sync2.get_text(), "b");
+        assert_eq!(sync2.get_clock().clock_value(0), 3);
+    }
+
+    #[test]
+    fn test_index_based_editing() {
+        let mut sync1 = SynchronizedText::new(0);
+        let mut sync2 = SynchronizedText::new(1);
+        let mut ops = vec![sync1.insert_str_at(0, "helo world").unwrap()];
+        ops.push(sync1.insert_str_at(3, "l").unwrap());
+        ops.extend(sync1.delete_range(5, 6).unwrap());
+        ops.push(sync1.insert_str_at(5, "!").unwrap());
+        assert_eq!(sync1.get_text(), "hello!");
+        assert_eq!(
+            sync1.insert_str_at(7, "?").unwrap_err(),
+            Error::IndexOutOfBounds { index: 7, len: 6 }
+        );
+        assert_eq!(
+            sync1.delete_range(4, 3).unwrap_err(),
+            Error::IndexOutOfBounds { index: 7, len: 6 }
+        );
+        assert_eq!(
+            sync1.delete_range(4, usize::MAX).unwrap_err(),
+            Error::IndexOutOfBounds {
+                index: usize::MAX,
+                len: 6
+            }
+        );
+
+        for op in &ops {
+            sync2.apply_operation(op).unwrap();
+        }
+        assert_eq!(sync2.get_text(), "hello!");
+
+        let bang = sync2.position_at(6).unwrap();
+        assert_eq!(sync2.index_of(bang), Some(6));
+        assert_eq!(sync2.position_at(7), None);
+        // the deleted " world" follows the '!' that was inserted right after the 'o'
+        assert_eq!(sync2.index_of([0, 0, 5, 5].into()), Some(6));
+        assert_eq!(sync2.index_of(S4Vector::root()), Some(0));
+    }
+
+    #[test]
+    fn test_insert_runs() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        let hello = sync0.insert_str_at(0, "hello").unwrap();
+        let world = sync0.insert_str_at(5, " world").unwrap();
+        assert_eq!(hello.span(), 5);
+        assert_eq!(sync0.get_clock().clock_value(0), 11);
+        assert_eq!(sync0.insert_str_at(0, "").unwrap_err(), Error::EmptyRun);
+
+        assert_eq!(sync1.apply_operation(&world), Ok(ApplyStatus::Buffered));
+        assert_eq!(sync1.apply_operation(&hello), Ok(ApplyStatus::Applied));
+        assert_eq!(
+            sync1.apply_operation(&hello),
+            Ok(ApplyStatus::AlreadyApplied)
+        );
+        assert_eq!(sync1.get_text(), "hello world");
+
+        // edits in the middle of a run only reference the characters they touch
+        let comma = sync1.insert_str_at(5, ",").unwrap();
+        let big = sync0.insert_str_at(6, "big ").unwrap();
+        let delete = sync1.delete_range(1, 1).unwrap().unwrap();
+        sync0.apply_operation(&comma).unwrap();
+        sync0.apply_operation(&delete).unwrap();
+        sync1.apply_operation(&big).unwrap();
+        assert_eq!(sync0.get_text(), "hllo, big world");
+        assert_eq!(sync1.get_positions(), sync0.get_positions());
+    }
+
+    #[test]
+    fn test_message_format() {
+        // inserts of single characters keep the field name of sites that only knew text
+        let mut sync = SynchronizedText::new(0);
+        for json in [
+            r#"{"sent_by":1,"op_clock":[0,2],"data":{"InsertRun":{"values":["h","i"],"insert_after":[0,0,0,0],"insert_position":[0,1,1,1]}}}"#,
+            r#"{"sent_by":1,"op_clock":[0,3],"data":{"Insert":{"character":"!","insert_after":[0,1,2,2],"insert_position":[0,1,3,3]}}}"#,
+        ] {
+            let operation: Operation = serde_json::from_str(json).unwrap();
+            sync.apply_operation(&operation).unwrap();
+            assert_eq!(serde_json::to_string(&operation).unwrap(), json);
+        }
+        assert_eq!(sync.get_text(), "hi!");
+
+        let mut numbers = SynchronizedSequence::<u32>::new(0);
+        let run = numbers
+            .local_insert_run(S4Vector::root(), vec![4, 2])
+            .unwrap();
+        let json = serde_json::to_string(&run).unwrap();
+        assert!(json.contains(r#""values":[4,2]"#));
+        let run: Operation<u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(run.span(), 2);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        let insert = sync0.insert_str_at(0, "hello").unwrap();
+        sync1.apply_operation(&insert).unwrap();
+        let comma = sync1.insert_str_at(5, ", world").unwrap();
+        sync0.apply_operation(&comma).unwrap();
+
+        let delete = sync0.delete_range(3, 5).unwrap().unwrap();
+        assert!(
+            matches!(&delete.data, OperationData::DeleteRange(positions) if positions.len() == 5)
+        );
+        assert_eq!(sync0.get_clock().clock_value(0), 6);
+        assert_eq!(sync0.get_text(), "helorld");
+        assert_eq!(sync0.delete_range(2, 0).unwrap().map(|op| op.span()), None);
+
+        sync1.apply_operation(&delete).unwrap();
+        assert_eq!(sync1.get_text(), "helorld");
+
+        // nothing is deleted if one of the positions is unknown
+        let missing = S4Vector::from([0, 2, 1, 1]);
+        let known = sync1.position_at(1).unwrap();
+        assert_eq!(
+            sync1.local_delete_range(&[known, missing]).unwrap_err(),
+            Error::UnknownPosition(missing)
+        );
+        assert_eq!(sync1.get_text(), "helorld");
+        assert_eq!(sync1.get_clock().clock_value(1), 7);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        let insert = sync0.insert_str_at(0, "cat").unwrap();
+        sync1.apply_operation(&insert).unwrap();
+        let c = sync0.position_at(1).unwrap();
+        let t = sync0.position_at(3).unwrap();
+
+        // concurrent updates of the same character, the one of site 1 has the larger timestamp
+        let update0 = sync0.local_update(c, 'b').unwrap();
+        let update1 = sync1.local_update(c, 'h').unwrap();
+        // a character deleted concurrently to its update stays deleted
+        let update_t = sync0.local_update(t, 'r').unwrap();
+        let delete_t = sync1.local_delete(t).unwrap();
+        assert_eq!(sync0.get_text(), "bar");
+        assert_eq!(sync1.get_text(), "ha");
+
+        for op in [&update1, &delete_t] {
+            sync0.apply_operation(op).unwrap();
+        }
+        for op in [&update0, &update_t] {
+            sync1.apply_operation(op).unwrap();
+        }
+        assert_eq!(sync0.get_text(), "ha");
+        assert_eq!(sync1.get_text(), "ha");
+        assert_eq!(sync0.snapshot().nodes, sync1.snapshot().nodes);
+    }
+
+    #[test]
+    fn test_marks() {
+        let run = |text: &str, marks: &[(&str, Option<&str>)]| FormattedRun {
+            text: text.to_owned(),
+            marks: marks
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_owned)))
+                .collect(),
+        };
+        let url = Some("https://example.com");
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        sync0.add_peer(1);
+        sync1.add_peer(0);
+        let insert = sync0.insert_str_at(0, "hello world").unwrap();
+        sync1.apply_operation(&insert).unwrap();
+
+        let mut ops0 = vec![sync0.add_mark(0, 5, "bold", None, true).unwrap().unwrap()];
+        let mut ops1 = vec![sync1
+            .add_mark(6, 5, "link", url.map(str::to_owned), false)
+            .unwrap()
+            .unwrap()];
+        // removing formatting the site hasn't seen yet still wins over the older mark
+        ops1.extend(sync1.remove_mark(0, 2, "bold", true).unwrap());
+        // text typed at the end of bold text is bold, at the end of a link it isn't
+        ops0.push(sync0.insert_str_at(5, "!").unwrap());
+        ops1.push(sync1.insert_str_at(11, "?").unwrap());
+        for op in &ops1 {
+            sync0.apply_operation(op).unwrap();
+        }
+        for op in &ops0 {
+            sync1.apply_operation(op).unwrap();
+        }
+
+        let expected = vec![
+            run("he", &[]),
+            run("llo!", &[("bold", None)]),
+            run(" ", &[]),
+            run("world", &[("link", url)]),
+            run("?", &[]),
+        ];
+        assert_eq!(sync0.formatted_runs(), expected);
+        assert_eq!(sync1.formatted_runs(), expected);
+        let restored = SynchronizedText::from_snapshot(2, sync1.snapshot()).unwrap();
+        assert_eq!(restored.formatted_runs(), expected);
+
+        // deleted characters marks are anchored at aren't purged
+        let delete = sync0.delete_range(1, 3).unwrap().unwrap();
+        sync1.apply_operation(&delete).unwrap();
+        assert_eq!(sync1.collect_garbage(), 2);
+        assert_eq!(sync1.formatted_runs()[0], run("h", &[]));
+        assert_eq!(sync1.formatted_runs()[1], run("o!", &[("bold", None)]));
+        assert_eq!(
+            sync1.add_mark(4, 10, "bold", None, true).unwrap_err(),
+            Error::IndexOutOfBounds { index: 14, len: 10 }
+        );
+        assert_eq!(
+            sync1.remove_mark(1, usize::MAX, "bold", true).unwrap_err(),
+            Error::IndexOutOfBounds {
+                index: usize::MAX,
+                len: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_structured_items() {
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        struct Todo {
+            title: String,
+            done: bool,
+        }
+        let todo = |title: &str| Todo {
+            title: title.to_owned(),
+            done: false,
+        };
+
+        let mut list0 = SynchronizedSequence::new(0);
+        let mut list1 = SynchronizedSequence::<Todo>::new(1);
+        let mut ops = vec![list0
+            .insert_at(0, vec![todo("milk"), todo("bread")])
+            .unwrap()
+            .unwrap()];
+        let milk = list0.position_at(1).unwrap();
+        ops.push(
+            list0
+                .local_update(
+                    milk,
+                    Todo {
+                        done: true,
+                        ..todo("milk")
+                    },
+                )
+                .unwrap(),
+        );
+        ops.push(list0.local_insert(milk, todo("eggs")).unwrap());
+
+        // operations of structured items go over the wire like text operations
+        for op in ops.iter().rev() {
+            let json = serde_json::to_string(op).unwrap();
+            list1
+                .apply_operation(&serde_json::from_str(&json).unwrap())
+                .unwrap();
+        }
+        assert_eq!(list1.values(), list0.values());
+        assert_eq!(
+            list1.values(),
+            vec![
+                Todo {
+                    done: true,
+                    ..todo("milk")
+                },
+                todo("eggs"),
+                todo("bread")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        sync0.add_peer(1);
+        let insert = sync0.insert_str_at(0, "abcd").unwrap();
+        sync1.apply_operation(&insert).unwrap();
+        let b = sync0.position_at(2).unwrap();
+        let delete = sync0.delete_range(1, 2).unwrap().unwrap();
+
+        // site 1 hasn't seen the deletion yet
+        assert_eq!(sync0.collect_garbage(), 0);
+
+        sync1.apply_operation(&delete).unwrap();
+        // sites that don't know their peers yet keep everything
+        assert_eq!(sync1.collect_garbage(), 0);
+        sync1.add_peer(0);
+        // the delete operation tells site 1 that site 0 has seen it as well
+        assert_eq!(sync1.collect_garbage(), 2);
+
+        let concurrent = sync1.insert_str_at(1, "x").unwrap();
+        let insert = sync0.local_insert(b, 'y').unwrap();
+        sync1.apply_operation(&insert).unwrap();
+        sync0.apply_operation(&concurrent).unwrap();
+
+        assert_eq!(sync0.collect_garbage(), 2);
+        assert_eq!(sync0.get_text(), sync1.get_text());
+        assert_eq!(sync0.get_positions(), sync1.get_positions());
+
+        let op = sync1.insert_str_at(2, "z").unwrap();
+        sync0.apply_operation(&op).unwrap();
+        assert_eq!(sync0.get_text(), sync1.get_text());
+    }
+
+    #[test]
+    fn test_bootstrap_from_snapshot() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut ops = vec![sync0.insert_str_at(0, "hello world").unwrap()];
+        ops.extend(sync0.delete_range(0, 6).unwrap());
+
+        let json = serde_json::to_string(&sync0.snapshot()).unwrap();
+        let mut sync1 =
+            SynchronizedText::from_snapshot(1, serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(sync1.get_text(), "world");
+        assert_eq!(sync1.get_positions(), sync0.get_positions());
+        for op in &ops {
+            assert_eq!(sync1.apply_operation(op), Ok(ApplyStatus::AlreadyApplied));
+        }
+
+        let op0 = sync0.insert_str_at(5, "!").unwrap();
+        let op1 = sync1.insert_str_at(0, "hi ").unwrap();
+        sync1.apply_operation(&op0).unwrap();
+        sync0.apply_operation(&op1).unwrap();
+        assert_eq!(sync0.get_text(), "hi world!");
+        assert_eq!(sync1.get_text(), "hi world!");
+    }
+
+    #[test]
+    fn test_merge_replicas() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        let mut sync2 = SynchronizedText::new(2);
+        let mut ops = vec![sync0.insert_str_at(0, "hello").unwrap()];
+        for op in &ops {
+            sync1.apply_operation(op).unwrap();
+        }
+
+        // both replicas edit without exchanging operations
+        ops.push(sync0.insert_str_at(5, " world").unwrap());
+        ops.extend(sync0.delete_range(0, 1).unwrap());
+        ops.push(sync1.insert_str_at(0, "o").unwrap());
+        ops.extend(sync1.delete_range(2, 3).unwrap());
+
+        sync0.merge(&sync1).unwrap();
+        sync1.merge(&sync0).unwrap();
+        for op in ops.iter().rev() {
+            sync2.apply_operation(op).unwrap();
+        }
+
+        assert_eq!(sync0.get_text(), "oo world");
+        assert_eq!(sync1.get_text(), "oo world");
+        assert_eq!(sync2.get_text(), "oo world");
+        assert_eq!(sync0.get_positions(), sync2.get_positions());
+        assert_eq!(sync1.get_positions(), sync2.get_positions());
+        assert_eq!(
+            sync1.get_clock().clock_values(),
+            sync0.get_clock().clock_values()
+        );
+    }
+
+    #[test]
+    fn test_operations_since() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync1 = SynchronizedText::new(1);
+        let insert = sync0.insert_str_at(0, "abc").unwrap();
+        sync1.apply_operation(&insert).unwrap();
+        let reconnecting = SynchronizedText::from_snapshot(2, sync1.snapshot()).unwrap();
+        sync1.insert_str_at(3, "d").unwrap();
+        sync0.delete_range(0, 1).unwrap();
+        sync0.merge(&sync1).unwrap();
+
+        let missed = sync1.operations_since(reconnecting.get_clock()).unwrap();
+        assert_eq!(missed.len(), 1);
+        let mut reconnecting = reconnecting;
+        for op in &missed {
+            reconnecting.apply_operation(op).unwrap();
+        }
+        assert_eq!(reconnecting.get_text(), "abcd");
+
+        // sync0 only knows the insertion of 'd' from the merge
+        let behind = VectorClock::from_parts(2, vec![3, 0, 0]).unwrap();
+        assert_eq!(
+            sync0.operations_since(&behind).unwrap_err(),
+            Error::MissingHistory
+        );
+        assert_eq!(
+            sync0
+                .operations_since(reconnecting.get_clock())
+                .unwrap()
+                .len(),
+            1
+        );
+        let missed = sync0.operations_since(sync1.get_clock()).unwrap();
+        assert_eq!(missed.len(), 1);
+        sync1.apply_operation(&missed[0]).unwrap();
+        assert_eq!(sync1.get_text(), "bcd");
+        assert_eq!(
+            sync0.operations_since(&VectorClock::new(3)).unwrap_err(),
+            Error::MissingHistory
+        );
+        assert_eq!(
+            sync1.operations_since(&VectorClock::new(3)).unwrap().len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_operation_errors() {
+        let mut sync = SynchronizedText::new(0);
+        let missing = S4Vector::from([0, 1, 1, 1]);
+        assert_eq!(
+            sync.local_insert(missing, 'a').unwrap_err(),
+            Error::UnknownPosition(missing)
+        );
+        assert_eq!(sync.get_clock().clock_value(0), 0);
+
+        let short_clock = Operation {
+            sent_by: 3,
+            op_clock: vec![0, 1],
+            data: OperationData::Delete(missing.to_array()),
+        };
+        assert_eq!(
+            sync.apply_operation(&short_clock),
+            Err(Error::MalformedClock { site_id: 3, len: 2 })
+        );
+
+        let unknown_reference = Operation {
+            sent_by: 1,
+            op_clock: vec![0, 1],
+            data: OperationData::Insert(InsertOperation {
+                value: 'a',
+                insert_after: missing.to_array(),
+                insert_position: [0, 1, 1, 1],
+            }),
+        };
+        assert_eq!(
+            sync.apply_operation(&unknown_reference),
+            Err(Error::UnknownPosition(missing))
+        );
+        assert_eq!(sync.get_clock().clock_value(1), 0);
+
+        // the positions of a run must fit in the clock
+        let overflowing_run = |values: Vec<char>| Operation {
+            sent_by: 1,
+            op_clock: vec![0, 2],
+            data: OperationData::InsertRun(InsertRunOperation {
+                values,
+                insert_after: [0, 0, 0, 0],
+                insert_position: [1, 1, u32::MAX, 1],
+            }),
+        };
+        assert_eq!(
+            sync.apply_operation(&overflowing_run(vec!['a', 'b'])),
+            Err(Error::MalformedClock { site_id: 1, len: 2 })
+        );
+        assert_eq!(
+            sync.apply_operation(&overflowing_run(Vec::new())),
+            Err(Error::EmptyRun)
+        );
+
+        // a buffered operation that fails is set aside and doesn't block the ones after it
+        let insert =
+            |sent_by: usize, op_clock: Vec<u32>, value, insert_after, insert_position| Operation {
+                sent_by,
+                op_clock,
+                data: OperationData::Insert(InsertOperation {
+                    value,
+                    insert_after,
+                    insert_position,
+                }),
+            };
+        let root = S4Vector::root().to_array();
+        let valid = insert(1, vec![0, 1], 'a', root, [0, 1, 1, 1]);
+        let failing = insert(1, vec![0, 2], 'b', [0, 2, 5, 5], [0, 1, 2, 2]);
+        let after_failing = insert(2, vec![0, 2, 1], 'c', root, [0, 2, 3, 1]);
+        assert_eq!(sync.apply_operation(&failing), Ok(ApplyStatus::Buffered));
+        assert_eq!(
+            sync.apply_operation(&after_failing),
+            Ok(ApplyStatus::Buffered)
+        );
+        assert_eq!(sync.apply_operation(&valid), Ok(ApplyStatus::Applied));
+        assert!(sync.pending_operations().is_empty());
+        let failed = sync.failed_operations();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].1, Error::UnknownPosition([0, 2, 5, 5].into()));
+        assert_eq!(sync.get_clock().clock_value(1), 2);
+
+        let later = insert(1, vec![0, 3, 1], 'd', [0, 1, 1, 1], [0, 1, 4, 3]);
+        assert_eq!(sync.apply_operation(&later), Ok(ApplyStatus::Applied));
+        assert_eq!(sync.values(), vec!['c', 'a', 'd']);
+        assert_eq!(sync.failed_operations().len(), 1);
+    }
 }
@@ -0,0 +1,617 @@
+//! A JSON-like document of nested maps, lists and texts. All containers of a document share one
+//! clock and one stream of operations. A container is identified by the timestamp of the
+//! operation that created it, the root map by the root position.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    causal::{deliver, CausalBuffer, CausalOperation, CausalReplica},
+    clocks::{S4Vector, VectorClock},
+    data_structure::{ApplyStatus, SynchronizedText},
+    lww::Slot,
+    rga::RGA,
+    Error,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+/// What an operation puts into a map or list. Containers are created empty.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Content {
+    Scalar(Scalar),
+    Map,
+    List,
+    Text,
+}
+
+/// The current value of a document or a part of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(Scalar),
+    Map(BTreeMap<String, Value>),
+    List(Vec<Value>),
+    Text(String),
+}
+
+/// Keys select values of maps, indices values of lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl From<&str> for PathSegment {
+    fn from(key: &str) -> Self {
+        PathSegment::Key(key.to_owned())
+    }
+}
+
+impl From<usize> for PathSegment {
+    fn from(index: usize) -> Self {
+        PathSegment::Index(index)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DocumentOperationData {
+    /// Assigns `value` to `key` of the map `object`, the write with the largest timestamp wins.
+    /// Containers that lose are kept but are no longer part of the document.
+    Set {
+        object: [u32; 4],
+        key: String,
+        value: Content,
+    },
+    Remove {
+        object: [u32; 4],
+        key: String,
+    },
+    /// Inserts `value` into the list `object`, at the position of the operation.
+    Insert {
+        object: [u32; 4],
+        insert_after: [u32; 4],
+        value: Content,
+    },
+    /// Inserts `text` into the text `object`. Like an [`crate::data_structure::InsertRunOperation`]
+    /// every character counts as an operation and gets a position of its own.
+    InsertText {
+        object: [u32; 4],
+        insert_after: [u32; 4],
+        text: String,
+    },
+    /// Deletes elements of the list or text `object`.
+    Delete {
+        object: [u32; 4],
+        positions: Vec<[u32; 4]>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentOperation {
+    pub sent_by: usize,
+    pub op_clock: Vec<u32>,
+    pub data: DocumentOperationData,
+}
+
+impl DocumentOperation {
+    /// Number of operations of the sending site this operation stands for.
+    pub fn span(&self) -> u32 {
+        match &self.data {
+            DocumentOperationData::InsertText { text, .. } => text.chars().count().max(1) as u32,
+            _ => 1,
+        }
+    }
+}
+
+impl CausalOperation for DocumentOperation {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    fn first_clock(&self) -> Vec<u32> {
+        let mut clock = self.op_clock.clone();
+        if let Some(value) = clock.get_mut(self.sent_by) {
+            *value = value.saturating_sub(self.span() - 1);
+        }
+        clock
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Scalar(Scalar),
+    Container(S4Vector),
+}
+
+enum Container {
+    Map(HashMap<String, Slot<Entry>>),
+    List(RGA<Entry>),
+    // only the remote operations of the text are used, with timestamps of the document
+    Text(Box<SynchronizedText>),
+}
+
+pub struct Document {
+    clock: VectorClock,
+    containers: HashMap<S4Vector, Container>,
+    pending: CausalBuffer<DocumentOperation>,
+}
+
+impl Document {
+    pub fn new(id: usize) -> Document {
+        Document {
+            clock: VectorClock::new(id),
+            containers: HashMap::from([(S4Vector::root(), Container::Map(HashMap::new()))]),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn value(&self) -> Value {
+        self.container_value(S4Vector::root())
+            .unwrap_or_else(|| Value::Map(BTreeMap::new()))
+    }
+
+    pub fn get(&self, path: &[PathSegment]) -> Option<Value> {
+        let entry = self.resolve(path).ok()?;
+        self.entry_value(&entry)
+    }
+
+    /// Assigns `value` to the map key `path` ends with.
+    pub fn set(
+        &mut self,
+        path: &[PathSegment],
+        value: Content,
+    ) -> Result<DocumentOperation, Error> {
+        let Some((PathSegment::Key(key), parent)) = path.split_last() else {
+            return Err(Error::InvalidPath);
+        };
+        let object = self.container_at(parent)?;
+        if !matches!(self.container(object)?, Container::Map(_)) {
+            return Err(Error::InvalidPath);
+        }
+        self.local_operation(
+            1,
+            DocumentOperationData::Set {
+                object: object.to_array(),
+                key: key.clone(),
+                value,
+            },
+        )
+    }
+
+    /// Removes the map key or list element `path` ends with.
+    pub fn remove(&mut self, path: &[PathSegment]) -> Result<DocumentOperation, Error> {
+        let (last, parent) = path.split_last().ok_or(Error::InvalidPath)?;
+        let object = self.container_at(parent)?;
+        let data = match (self.container(object)?, last) {
+            (Container::Map(_), PathSegment::Key(key)) => DocumentOperationData::Remove {
+                object: object.to_array(),
+                key: key.clone(),
+            },
+            (Container::List(list), PathSegment::Index(index)) => {
+                let position = index
+                    .checked_add(1)
+                    .and_then(|idx| list.position_at(idx))
+                    .ok_or(Error::IndexOutOfBounds {
+                        index: *index,
+                        len: list.len(),
+                    })?;
+                DocumentOperationData::Delete {
+                    object: object.to_array(),
+                    positions: vec![position.to_array()],
+                }
+            }
+            _ => return Err(Error::InvalidPath),
+        };
+        self.local_operation(1, data)
+    }
+
+    /// Inserts `value` into a list at the index `path` ends with.
+    pub fn insert(
+        &mut self,
+        path: &[PathSegment],
+        value: Content,
+    ) -> Result<DocumentOperation, Error> {
+        let Some((PathSegment::Index(index), parent)) = path.split_last() else {
+            return Err(Error::InvalidPath);
+        };
+        let object = self.container_at(parent)?;
+        let Container::List(list) = self.container(object)? else {
+            return Err(Error::InvalidPath);
+        };
+        let insert_after = list.position_at(*index).ok_or(Error::IndexOutOfBounds {
+            index: *index,
+            len: list.len(),
+        })?;
+        self.local_operation(
+            1,
+            DocumentOperationData::Insert {
+                object: object.to_array(),
+                insert_after: insert_after.to_array(),
+                value,
+            },
+        )
+    }
+
+    /// Inserts `text` at `index` of the text at `path`, `None` if `text` is empty.
+    pub fn insert_text(
+        &mut self,
+        path: &[PathSegment],
+        index: usize,
+        text: &str,
+    ) -> Result<Option<DocumentOperation>, Error> {
+        let object = self.container_at(path)?;
+        let Container::Text(container) = self.container(object)? else {
+            return Err(Error::InvalidPath);
+        };
+        let insert_after = container
+            .position_at(index)
+            .ok_or(Error::IndexOutOfBounds {
+                index,
+                len: container.len(),
+            })?;
+        if text.is_empty() {
+            return Ok(None);
+        }
+        self.local_operation(
+            text.chars().count() as u32,
+            DocumentOperationData::InsertText {
+                object: object.to_array(),
+                insert_after: insert_after.to_array(),
+                text: text.to_owned(),
+            },
+        )
+        .map(Some)
+    }
+
+    /// Deletes `len` characters starting at `index` of the text at `path`.
+    pub fn delete_text(
+        &mut self,
+        path: &[PathSegment],
+        index: usize,
+        len: usize,
+    ) -> Result<Option<DocumentOperation>, Error> {
+        let object = self.container_at(path)?;
+        let Container::Text(text) = self.container(object)? else {
+            return Err(Error::InvalidPath);
+        };
+        let Some(end) = index.checked_add(len).filter(|end| *end <= text.len()) else {
+            return Err(Error::IndexOutOfBounds {
+                index: index.saturating_add(len),
+                len: text.len(),
+            });
+        };
+        if len == 0 {
+            return Ok(None);
+        }
+        let positions = (index + 1..=end)
+            .filter_map(|idx| text.position_at(idx))
+            .map(|pos| pos.to_array())
+            .collect();
+        self.local_operation(
+            1,
+            DocumentOperationData::Delete {
+                object: object.to_array(),
+                positions,
+            },
+        )
+        .map(Some)
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[DocumentOperation] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(DocumentOperation, Error)] {
+        self.pending.failed()
+    }
+
+    /// Delivers `operation` like [`SynchronizedSequence::apply_operation`].
+    ///
+    /// [`SynchronizedSequence::apply_operation`]: crate::data_structure::SynchronizedSequence::apply_operation
+    pub fn apply_operation(&mut self, operation: &DocumentOperation) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+
+    fn local_operation(
+        &mut self,
+        span: u32,
+        data: DocumentOperationData,
+    ) -> Result<DocumentOperation, Error> {
+        for _ in 0..span {
+            self.clock.increase();
+        }
+        let operation = DocumentOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data,
+        };
+        self.integrate(&operation)?;
+        Ok(operation)
+    }
+
+    fn map_mut(&mut self, object: S4Vector) -> Result<&mut HashMap<String, Slot<Entry>>, Error> {
+        match self.containers.get_mut(&object) {
+            Some(Container::Map(map)) => Ok(map),
+            _ => Err(Error::UnknownPosition(object)),
+        }
+    }
+
+    fn create(&mut self, position: S4Vector, content: &Content) {
+        let container = match content {
+            Content::Scalar(_) => return,
+            Content::Map => Container::Map(HashMap::new()),
+            Content::List => Container::List(RGA::new()),
+            Content::Text => Container::Text(Box::new(SynchronizedText::new(self.clock.id()))),
+        };
+        self.containers.insert(position, container);
+    }
+
+    fn container_at(&self, path: &[PathSegment]) -> Result<S4Vector, Error> {
+        match self.resolve(path)? {
+            Entry::Container(id) => Ok(id),
+            Entry::Scalar(_) => Err(Error::InvalidPath),
+        }
+    }
+
+    fn resolve(&self, path: &[PathSegment]) -> Result<Entry, Error> {
+        let mut current = Entry::Container(S4Vector::root());
+        for segment in path {
+            let Entry::Container(id) = current else {
+                return Err(Error::InvalidPath);
+            };
+            current = match (self.container(id)?, segment) {
+                (Container::Map(map), PathSegment::Key(key)) => {
+                    map.get(key).and_then(|slot| slot.value.clone())
+                }
+                (Container::List(list), PathSegment::Index(index)) => list
+                    .position_at(index + 1)
+                    .and_then(|pos| list.get(pos))
+                    .cloned(),
+                _ => None,
+            }
+            .ok_or(Error::InvalidPath)?;
+        }
+        Ok(current)
+    }
+
+    fn container(&self, id: S4Vector) -> Result<&Container, Error> {
+        self.containers.get(&id).ok_or(Error::UnknownPosition(id))
+    }
+
+    // entries of containers that don't exist are left out
+    fn entry_value(&self, entry: &Entry) -> Option<Value> {
+        match entry {
+            Entry::Scalar(scalar) => Some(Value::Scalar(scalar.clone())),
+            Entry::Container(id) => self.container_value(*id),
+        }
+    }
+
+    fn container_value(&self, id: S4Vector) -> Option<Value> {
+        Some(match self.containers.get(&id)? {
+            Container::Map(map) => Value::Map(
+                map.iter()
+                    .filter_map(|(key, slot)| {
+                        Some((key.clone(), self.entry_value(slot.value.as_ref()?)?))
+                    })
+                    .collect(),
+            ),
+            Container::List(list) => Value::List(
+                list.iter()
+                    .filter_map(|(_, entry)| self.entry_value(&entry?))
+                    .collect(),
+            ),
+            Container::Text(text) => Value::Text(text.get_text()),
+        })
+    }
+}
+
+impl CausalReplica for Document {
+    type Operation = DocumentOperation;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<DocumentOperation>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &DocumentOperation) -> Result<(), Error> {
+        // positions and timestamps start with the first of the spanned operations
+        let first =
+            VectorClock::from_parts(operation.sent_by, operation.first_clock())?.to_s4vector();
+        match &operation.data {
+            DocumentOperationData::Set { object, key, value } => {
+                self.map_mut((*object).into())?
+                    .entry(key.clone())
+                    .or_insert_with(Slot::empty)
+                    .assign(Some(entry(first, value)), first);
+                self.create(first, value);
+            }
+            DocumentOperationData::Remove { object, key } => self
+                .map_mut((*object).into())?
+                .entry(key.clone())
+                .or_insert_with(Slot::empty)
+                .assign(None, first),
+            DocumentOperationData::Insert {
+                object,
+                insert_after,
+                value,
+            } => {
+                let object = S4Vector::from(*object);
+                let Some(Container::List(list)) = self.containers.get_mut(&object) else {
+                    return Err(Error::UnknownPosition(object));
+                };
+                list.insert((*insert_after).into(), first, entry(first, value))?;
+                self.create(first, value);
+            }
+            DocumentOperationData::InsertText {
+                object,
+                insert_after,
+                text,
+            } => {
+                let object = S4Vector::from(*object);
+                let Some(Container::Text(container)) = self.containers.get_mut(&object) else {
+                    return Err(Error::UnknownPosition(object));
+                };
+                container.remote_insert_run(
+                    first,
+                    (*insert_after).into(),
+                    text.chars().collect(),
+                )?;
+            }
+            DocumentOperationData::Delete { object, positions } => {
+                let object = S4Vector::from(*object);
+                let positions: Vec<S4Vector> = positions.iter().map(|pos| (*pos).into()).collect();
+                match self.containers.get_mut(&object) {
+                    Some(Container::List(list)) => list.delete_range(&positions, first)?,
+                    Some(Container::Text(text)) => text.remote_delete_range(first, &positions)?,
+                    _ => return Err(Error::UnknownPosition(object)),
+                }
+            }
+        }
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+// containers are created at the position of the operation that puts them into the document
+fn entry(position: S4Vector, content: &Content) -> Entry {
+    match content {
+        Content::Scalar(scalar) => Entry::Scalar(scalar.clone()),
+        _ => Entry::Container(position),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: &str) -> Content {
+        Content::Scalar(Scalar::String(value.to_owned()))
+    }
+
+    #[test]
+    fn test_nested_document() {
+        let mut doc0 = Document::new(0);
+        let mut doc1 = Document::new(1);
+        let todo_title: &[PathSegment] = &["todos".into(), 0.into(), "title".into()];
+        let mut ops = vec![
+            doc0.set(&["title".into()], text("Groceries")).unwrap(),
+            doc0.set(&["todos".into()], Content::List).unwrap(),
+            doc0.insert(&["todos".into(), 0.into()], Content::Map)
+                .unwrap(),
+            doc0.set(todo_title, text("milk")).unwrap(),
+            doc0.set(&["notes".into()], Content::Text).unwrap(),
+        ];
+        ops.extend(doc0.insert_text(&["notes".into()], 0, "buy früh").unwrap());
+        ops.extend(doc0.delete_text(&["notes".into()], 3, 1).unwrap());
+
+        // operations go over the wire as one stream and may arrive in any order
+        for op in ops.iter().rev() {
+            let json = serde_json::to_string(op).unwrap();
+            doc1.apply_operation(&serde_json::from_str(&json).unwrap())
+                .unwrap();
+        }
+        assert!(doc1.pending_operations().is_empty());
+        assert_eq!(doc1.value(), doc0.value());
+        assert_eq!(
+            doc1.get(todo_title),
+            Some(Value::Scalar(Scalar::String("milk".to_owned())))
+        );
+        assert_eq!(
+            doc1.get(&["notes".into()]),
+            Some(Value::Text("buyfrüh".to_owned()))
+        );
+        assert_eq!(
+            doc1.insert_text(&["title".into()], 0, "x").unwrap_err(),
+            Error::InvalidPath
+        );
+        assert_eq!(
+            doc1.remove(&["todos".into(), 1.into()]).unwrap_err(),
+            Error::IndexOutOfBounds { index: 1, len: 1 }
+        );
+        assert_eq!(
+            doc1.delete_text(&["notes".into()], 1, usize::MAX)
+                .unwrap_err(),
+            Error::IndexOutOfBounds {
+                index: usize::MAX,
+                len: 7
+            }
+        );
+
+        // operations on containers that don't exist are rejected
+        let unknown = DocumentOperation {
+            sent_by: 2,
+            op_clock: vec![0, 0, 1],
+            data: DocumentOperationData::InsertText {
+                object: [0, 2, 9, 9],
+                insert_after: S4Vector::root().to_array(),
+                text: "x".to_owned(),
+            },
+        };
+        assert_eq!(
+            doc1.apply_operation(&unknown).unwrap_err(),
+            Error::UnknownPosition([0, 2, 9, 9].into())
+        );
+        assert_eq!(doc1.value(), doc0.value());
+    }
+
+    #[test]
+    fn test_concurrent_edits() {
+        let mut doc0 = Document::new(0);
+        let mut doc1 = Document::new(1);
+        let base = [
+            doc0.set(&["todos".into()], Content::List).unwrap(),
+            doc0.insert(&["todos".into(), 0.into()], Content::Map)
+                .unwrap(),
+            doc0.set(&["notes".into()], Content::Text).unwrap(),
+        ];
+        for op in &base {
+            doc1.apply_operation(op).unwrap();
+        }
+
+        let mut ops0 = vec![doc0.set(&["title".into()], text("Plan")).unwrap()];
+        ops0.push(
+            doc0.set(
+                &["todos".into(), 0.into(), "done".into()],
+                Content::Scalar(Scalar::Bool(true)),
+            )
+            .unwrap(),
+        );
+        ops0.extend(doc0.insert_text(&["notes".into()], 0, "ab").unwrap());
+        let mut ops1 = vec![doc1.set(&["title".into()], text("Agenda")).unwrap()];
+        ops1.push(doc1.remove(&["todos".into(), 0.into()]).unwrap());
+        ops1.extend(doc1.insert_text(&["notes".into()], 0, "xy").unwrap());
+
+        for op in &ops1 {
+            doc0.apply_operation(op).unwrap();
+        }
+        for op in &ops0 {
+            doc1.apply_operation(op).unwrap();
+        }
+        assert_eq!(doc0.value(), doc1.value());
+        // the title of site 1 has the larger timestamp
+        assert_eq!(
+            doc0.get(&["title".into()]),
+            Some(Value::Scalar(Scalar::String("Agenda".to_owned())))
+        );
+        assert_eq!(doc0.get(&["todos".into()]), Some(Value::List(vec![])));
+        let Some(Value::Text(notes)) = doc0.get(&["notes".into()]) else {
+            panic!("notes are a text");
+        };
+        assert!(notes == "abxy" || notes == "xyab");
+    }
+}
@@ -0,0 +1,738 @@
+//! Compact binary encoding of [`Operation`]s.
+//!
+//! An encoded batch starts with the format version, followed by one entry per operation. Every
+//! entry stores its clock as difference to the clock of the entry before it, and consecutive
+//! inserts a site typed one after another are collapsed into a single entry holding the text.
+
+use crate::{
+    clocks::{S4Vector, VectorClock},
+    data_structure::{
+        InsertOperation, InsertRunOperation, MoveOperation, Operation, OperationData,
+        UpdateOperation,
+    },
+    marks::{Anchor, MarkOperation},
+    Error,
+};
+
+const VERSION: u8 = 1;
+
+const INSERT: u8 = 0;
+const DELETE: u8 = 1;
+const INSERT_TEXT: u8 = 2;
+// an insert whose position can't be derived from the clock of the operation
+const INSERT_AT_POSITION: u8 = 3;
+const INSERT_RUN: u8 = 4;
+const INSERT_RUN_AT_POSITION: u8 = 5;
+const DELETE_RANGE: u8 = 6;
+const UPDATE: u8 = 7;
+const MOVE: u8 = 8;
+const ADD_MARK: u8 = 9;
+const REMOVE_MARK: u8 = 10;
+const RESTORE: u8 = 11;
+
+// ranges are expanded while decoding, so a few bytes could otherwise ask for any number of them
+const MAX_POSITIONS: usize = 1 << 20;
+
+const ANCHOR_BEFORE: u8 = 0;
+const ANCHOR_AFTER: u8 = 1;
+const ANCHOR_END: u8 = 2;
+
+impl Operation {
+    pub fn encode(&self) -> Vec<u8> {
+        encode_operations(std::slice::from_ref(self))
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Operation, Error> {
+        let mut operations = decode_operations(bytes)?;
+        if operations.len() != 1 {
+            return Err(Error::InvalidEncoding);
+        }
+        Ok(operations.remove(0))
+    }
+}
+
+pub fn encode_operations(operations: &[Operation]) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: vec![VERSION],
+        clock: Vec::new(),
+    };
+    let mut idx = 0;
+    while idx < operations.len() {
+        let operation = &operations[idx];
+        match &operation.data {
+            OperationData::Insert(insert) => {
+                let run = typed_run_len(&operations[idx..]);
+                if run > 1 {
+                    writer.header(INSERT_TEXT, operation);
+                    writer.position(insert.insert_after);
+                    let text: String = operations[idx..idx + run]
+                        .iter()
+                        .map(|op| match &op.data {
+                            OperationData::Insert(insert) => insert.value,
+                            _ => unreachable!("runs only contain inserts"),
+                        })
+                        .collect();
+                    writer.text(&text);
+                    writer.clock = operations[idx + run - 1].op_clock.clone();
+                    idx += run;
+                    continue;
+                }
+                let explicit = insert.insert_position != implied_position(operation);
+                let tag = if explicit { INSERT_AT_POSITION } else { INSERT };
+                writer.header(tag, operation);
+                writer.position(insert.insert_after);
+                if explicit {
+                    writer.position(insert.insert_position);
+                }
+                writer.varint(insert.value as u64);
+            }
+            OperationData::Delete(position) => {
+                writer.header(DELETE, operation);
+                writer.position(*position);
+            }
+            OperationData::Update(update) => {
+                writer.header(UPDATE, operation);
+                writer.position(update.position);
+                writer.varint(update.value as u64);
+            }
+            OperationData::Move(data) => {
+                writer.header(MOVE, operation);
+                writer.position(data.position);
+                writer.position(data.insert_after);
+            }
+            OperationData::DeleteRange(positions) => {
+                writer.header(DELETE_RANGE, operation);
+                writer.positions(positions);
+            }
+            OperationData::Restore(positions) => {
+                writer.header(RESTORE, operation);
+                writer.positions(positions);
+            }
+            OperationData::InsertRun(run) => {
+                let explicit = run.insert_position != implied_run_position(operation);
+                let tag = if explicit {
+                    INSERT_RUN_AT_POSITION
+                } else {
+                    INSERT_RUN
+                };
+                writer.header(tag, operation);
+                writer.position(run.insert_after);
+                if explicit {
+                    writer.position(run.insert_position);
+                }
+                writer.text(&run.values.iter().collect::<String>());
+            }
+            OperationData::AddMark(mark) => {
+                writer.header(ADD_MARK, operation);
+                writer.mark(mark);
+            }
+            OperationData::RemoveMark(mark) => {
+                writer.header(REMOVE_MARK, operation);
+                writer.mark(mark);
+            }
+        }
+        idx += 1;
+    }
+    writer.bytes
+}
+
+pub fn decode_operations(bytes: &[u8]) -> Result<Vec<Operation>, Error> {
+    let (version, bytes) = bytes.split_first().ok_or(Error::InvalidEncoding)?;
+    if *version != VERSION {
+        return Err(Error::UnsupportedEncodingVersion(*version));
+    }
+    let mut reader = Reader {
+        bytes,
+        clock: Vec::new(),
+        positions_left: MAX_POSITIONS,
+    };
+    let mut operations = Vec::new();
+    while !reader.bytes.is_empty() {
+        let tag = reader.byte()?;
+        let sent_by = reader.varint()? as usize;
+        let op_clock = reader.clock()?;
+        VectorClock::from_parts(sent_by, op_clock.clone())?;
+
+        match tag {
+            INSERT | INSERT_AT_POSITION => {
+                let insert_after = reader.position()?;
+                let mut operation = Operation {
+                    sent_by,
+                    op_clock,
+                    data: OperationData::Delete(insert_after),
+                };
+                let insert_position = if tag == INSERT_AT_POSITION {
+                    reader.position()?
+                } else {
+                    implied_position(&operation)
+                };
+                let value = reader.character()?;
+                operation.data = OperationData::Insert(InsertOperation {
+                    value,
+                    insert_after,
+                    insert_position,
+                });
+                operations.push(operation);
+            }
+            DELETE => {
+                let position = reader.position()?;
+                operations.push(Operation {
+                    sent_by,
+                    op_clock,
+                    data: OperationData::Delete(position),
+                });
+            }
+            UPDATE => {
+                let position = reader.position()?;
+                operations.push(Operation {
+                    sent_by,
+                    op_clock,
+                    data: OperationData::Update(UpdateOperation {
+                        value: reader.character()?,
+                        position,
+                    }),
+                });
+            }
+            MOVE => {
+                operations.push(Operation {
+                    sent_by,
+                    op_clock,
+                    data: OperationData::Move(MoveOperation {
+                        position: reader.position()?,
+                        insert_after: reader.position()?,
+                    }),
+                });
+            }
+            DELETE_RANGE => operations.push(Operation {
+                sent_by,
+                data: OperationData::DeleteRange(reader.positions(&op_clock)?),
+                op_clock,
+            }),
+            RESTORE => operations.push(Operation {
+                sent_by,
+                data: OperationData::Restore(reader.positions(&op_clock)?),
+                op_clock,
+            }),
+            INSERT_TEXT => {
+                let mut insert_after = reader.position()?;
+                let text = reader.text()?;
+                let mut op_clock = op_clock;
+                for (idx, character) in text.chars().enumerate() {
+                    if idx > 0 {
+                        op_clock[sent_by] = op_clock[sent_by]
+                            .checked_add(1)
+                            .ok_or(Error::InvalidEncoding)?;
+                    }
+                    let mut operation = Operation {
+                        sent_by,
+                        op_clock: op_clock.clone(),
+                        data: OperationData::Delete(insert_after),
+                    };
+                    let insert_position = implied_position(&operation);
+                    operation.data = OperationData::Insert(InsertOperation {
+                        value: character,
+                        insert_after,
+                        insert_position,
+                    });
+                    operations.push(operation);
+                    insert_after = insert_position;
+                }
+                reader.clock = op_clock;
+            }
+            INSERT_RUN | INSERT_RUN_AT_POSITION => {
+                let insert_after = reader.position()?;
+                let explicit_position = if tag == INSERT_RUN_AT_POSITION {
+                    Some(reader.position()?)
+                } else {
+                    None
+                };
+                let mut operation = Operation {
+                    sent_by,
+                    op_clock,
+                    data: OperationData::InsertRun(InsertRunOperation {
+                        values: reader.text()?.chars().collect(),
+                        insert_after,
+                        insert_position: [0; 4],
+                    }),
+                };
+                let insert_position =
+                    explicit_position.unwrap_or_else(|| implied_run_position(&operation));
+                if let OperationData::InsertRun(run) = &mut operation.data {
+                    run.insert_position = insert_position;
+                }
+                operations.push(operation);
+            }
+            ADD_MARK => operations.push(Operation {
+                sent_by,
+                op_clock,
+                data: OperationData::AddMark(reader.mark()?),
+            }),
+            REMOVE_MARK => operations.push(Operation {
+                sent_by,
+                op_clock,
+                data: OperationData::RemoveMark(reader.mark()?),
+            }),
+            _ => return Err(Error::InvalidEncoding),
+        }
+    }
+    Ok(operations)
+}
+
+// the position `local_insert` assigns to an insert with the clock of `operation`
+fn implied_position(operation: &Operation) -> [u32; 4] {
+    VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())
+        .map(|clock| clock.to_s4vector().to_array())
+        .unwrap_or(S4Vector::root().to_array())
+}
+
+// the position `local_insert_str` assigns to the first character of a run
+fn implied_run_position(operation: &Operation) -> [u32; 4] {
+    let [ssn, sid, sum, seq] = implied_position(operation);
+    let offset = operation.span() - 1;
+    [ssn, sid, sum.wrapping_sub(offset), seq.wrapping_sub(offset)]
+}
+
+// number of operations at the start of `operations` that insert one character after another
+fn typed_run_len(operations: &[Operation]) -> usize {
+    let mut len = 0;
+    let mut previous: Option<&Operation> = None;
+    for operation in operations {
+        let OperationData::Insert(insert) = &operation.data else {
+            break;
+        };
+        if insert.insert_position != implied_position(operation) {
+            break;
+        }
+        if let Some(previous) = previous {
+            let OperationData::Insert(previous_insert) = &previous.data else {
+                unreachable!("runs only contain inserts")
+            };
+            if operation.sent_by != previous.sent_by {
+                break;
+            }
+            // a malformed clock or a full entry ends the run
+            let mut expected_clock = previous.op_clock.clone();
+            let Some(sequence) = expected_clock.get_mut(operation.sent_by) else {
+                break;
+            };
+            let Some(next) = sequence.checked_add(1) else {
+                break;
+            };
+            *sequence = next;
+            if operation.op_clock != expected_clock
+                || insert.insert_after != previous_insert.insert_position
+            {
+                break;
+            }
+        }
+        previous = Some(operation);
+        len += 1;
+    }
+    len
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    clock: Vec<u32>,
+}
+
+impl Writer {
+    fn header(&mut self, tag: u8, operation: &Operation) {
+        self.bytes.push(tag);
+        self.varint(operation.sent_by as u64);
+        self.varint(operation.op_clock.len() as u64);
+        for (idx, value) in operation.op_clock.iter().enumerate() {
+            let previous = self.clock.get(idx).copied().unwrap_or(0);
+            self.varint(zigzag(*value as i64 - previous as i64));
+        }
+        self.clock = operation.op_clock.clone();
+    }
+
+    fn position(&mut self, position: [u32; 4]) {
+        for value in position {
+            self.varint(value as u64);
+        }
+    }
+
+    // positions of text inserted as a run are consecutive and stored as first position and
+    // length
+    fn positions(&mut self, positions: &[[u32; 4]]) {
+        let mut runs: Vec<(S4Vector, u32)> = Vec::new();
+        for position in positions.iter().map(|pos| S4Vector::from(*pos)) {
+            match runs.last_mut() {
+                Some((first, len)) if first.run_position(*len) == position => *len += 1,
+                _ => runs.push((position, 1)),
+            }
+        }
+        self.varint(runs.len() as u64);
+        for (first, len) in runs {
+            self.position(first.to_array());
+            self.varint(len as u64);
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        self.varint(text.len() as u64);
+        self.bytes.extend_from_slice(text.as_bytes());
+    }
+
+    fn mark(&mut self, mark: &MarkOperation) {
+        self.text(&mark.name);
+        match &mark.value {
+            Some(value) => {
+                self.bytes.push(1);
+                self.text(value);
+            }
+            None => self.bytes.push(0),
+        }
+        self.anchor(mark.start);
+        self.anchor(mark.end);
+    }
+
+    fn anchor(&mut self, anchor: Anchor) {
+        match anchor {
+            Anchor::Before(position) => {
+                self.bytes.push(ANCHOR_BEFORE);
+                self.position(position);
+            }
+            Anchor::After(position) => {
+                self.bytes.push(ANCHOR_AFTER);
+                self.position(position);
+            }
+            Anchor::End => self.bytes.push(ANCHOR_END),
+        }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    clock: Vec<u32>,
+    // how many more positions the message may contain
+    positions_left: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let (byte, rest) = self.bytes.split_first().ok_or(Error::InvalidEncoding)?;
+        self.bytes = rest;
+        Ok(*byte)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::InvalidEncoding);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn text(&mut self) -> Result<&'a str, Error> {
+        let len = self.varint()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| Error::InvalidEncoding)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::InvalidEncoding)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        u32::try_from(self.varint()?).map_err(|_| Error::InvalidEncoding)
+    }
+
+    fn character(&mut self) -> Result<char, Error> {
+        char::from_u32(self.u32()?).ok_or(Error::InvalidEncoding)
+    }
+
+    fn clock(&mut self) -> Result<Vec<u32>, Error> {
+        let len = self.varint()? as usize;
+        if len > self.bytes.len() {
+            return Err(Error::InvalidEncoding);
+        }
+        let mut clock = Vec::with_capacity(len);
+        for idx in 0..len {
+            let previous = self.clock.get(idx).copied().unwrap_or(0) as i64;
+            let value = previous
+                .checked_add(unzigzag(self.varint()?))
+                .and_then(|value| u32::try_from(value).ok())
+                .ok_or(Error::InvalidEncoding)?;
+            clock.push(value);
+        }
+        self.clock = clock.clone();
+        Ok(clock)
+    }
+
+    fn position(&mut self) -> Result<[u32; 4], Error> {
+        Ok([self.u32()?, self.u32()?, self.u32()?, self.u32()?])
+    }
+
+    fn positions(&mut self, op_clock: &[u32]) -> Result<Vec<[u32; 4]>, Error> {
+        let mut positions = Vec::new();
+        for _ in 0..self.varint()? {
+            let first = S4Vector::from(self.position()?);
+            let len = self.u32()?;
+            // the sender can only refer to positions its clock covers
+            let known = op_clock.get(first.sid as usize).copied().unwrap_or(0);
+            let last_seq = first.seq.checked_add(len.saturating_sub(1));
+            if len == 0
+                || last_seq.is_none_or(|seq| seq > known)
+                || first.sum.checked_add(len).is_none()
+                || len as usize > self.positions_left
+            {
+                return Err(Error::InvalidEncoding);
+            }
+            self.positions_left -= len as usize;
+            positions.extend((0..len).map(|offset| first.run_position(offset).to_array()));
+        }
+        Ok(positions)
+    }
+
+    fn mark(&mut self) -> Result<MarkOperation, Error> {
+        let name = self.text()?.to_owned();
+        let value = match self.byte()? {
+            0 => None,
+            1 => Some(self.text()?.to_owned()),
+            _ => return Err(Error::InvalidEncoding),
+        };
+        Ok(MarkOperation {
+            name,
+            value,
+            start: self.anchor()?,
+            end: self.anchor()?,
+        })
+    }
+
+    fn anchor(&mut self) -> Result<Anchor, Error> {
+        match self.byte()? {
+            ANCHOR_BEFORE => Ok(Anchor::Before(self.position()?)),
+            ANCHOR_AFTER => Ok(Anchor::After(self.position()?)),
+            ANCHOR_END => Ok(Anchor::End),
+            _ => Err(Error::InvalidEncoding),
+        }
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::SynchronizedText;
+
+    fn assert_same(decoded: &[Operation], operations: &[Operation]) {
+        assert_eq!(
+            serde_json::to_string(decoded).unwrap(),
+            serde_json::to_string(operations).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut sync0 = SynchronizedText::new(0);
+        let mut sync2 = SynchronizedText::new(2);
+        let mut operations = Vec::new();
+        let mut insert_after = S4Vector::root();
+        for character in "héllo wörld ✓".chars() {
+            operations.push(sync0.local_insert(insert_after, character).unwrap());
+            insert_after = sync0.get_clock().to_s4vector();
+        }
+        for op in &operations {
+            sync2.apply_operation(op).unwrap();
+        }
+        operations.push(sync2.insert_str_at(5, ",").unwrap());
+        operations.push(sync2.insert_str_at(7, "big ").unwrap());
+        let deleted = sync2.delete_range(0, 2).unwrap().unwrap();
+        let OperationData::DeleteRange(positions) = &deleted.data else {
+            panic!("expected a range");
+        };
+        let positions: Vec<S4Vector> = positions.iter().map(|pos| (*pos).into()).collect();
+        operations.push(deleted.clone());
+        operations.push(sync2.insert_str_at(0, "H").unwrap());
+        operations.extend(sync2.local_restore(&positions).unwrap());
+        let position = sync2.position_at(1).unwrap();
+        operations.push(sync2.local_update(position, 'h').unwrap());
+        let end = sync2.position_at(sync2.len()).unwrap();
+        operations.push(sync2.local_move(position, end).unwrap());
+        operations.extend(sync2.add_mark(0, 3, "bold", None, true).unwrap());
+        let link = Some("https://example.com".to_owned());
+        operations.extend(sync2.add_mark(2, 4, "link", link, false).unwrap());
+        operations.extend(sync2.remove_mark(1, 1, "bold", true).unwrap());
+
+        let encoded = encode_operations(&operations);
+        assert_same(&decode_operations(&encoded).unwrap(), &operations);
+        assert!(encoded.len() < serde_json::to_string(&operations).unwrap().len() / 10);
+
+        for op in &operations {
+            assert_same(
+                &[Operation::decode(&op.encode()).unwrap()],
+                std::slice::from_ref(op),
+            );
+        }
+    }
+
+    #[test]
+    fn test_explicit_positions() {
+        let operations = [
+            Operation {
+                sent_by: 1,
+                op_clock: vec![4, 2],
+                data: OperationData::Insert(InsertOperation {
+                    value: 'x',
+                    insert_after: [0, 0, 1, 1],
+                    insert_position: [0, 1, 9, 2],
+                }),
+            },
+            Operation {
+                sent_by: 1,
+                op_clock: vec![4, 4],
+                data: OperationData::InsertRun(InsertRunOperation {
+                    values: vec!['y', 'z'],
+                    insert_after: [0, 1, 9, 2],
+                    insert_position: [0, 1, 12, 3],
+                }),
+            },
+        ];
+        for operation in &operations {
+            assert_same(
+                &[Operation::decode(&operation.encode()).unwrap()],
+                std::slice::from_ref(operation),
+            );
+        }
+        assert_same(
+            &decode_operations(&encode_operations(&operations)).unwrap(),
+            &operations,
+        );
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert_eq!(decode_operations(&[]).unwrap_err(), Error::InvalidEncoding);
+        assert_eq!(
+            decode_operations(&[7]).unwrap_err(),
+            Error::UnsupportedEncodingVersion(7)
+        );
+        let mut sync = SynchronizedText::new(0);
+        let run = sync.insert_str_at(0, "ab").unwrap();
+        let delete = sync.delete_range(0, 1).unwrap().unwrap();
+        let encoded = encode_operations(&[run.clone(), delete]);
+        let run = run.encode();
+        assert_eq!(
+            decode_operations(&run[..run.len() - 1]).unwrap_err(),
+            Error::InvalidEncoding
+        );
+        assert_eq!(
+            Operation::decode(&encoded).unwrap_err(),
+            Error::InvalidEncoding
+        );
+        // site 3 has no entry in a clock of length 1
+        assert_eq!(
+            decode_operations(&[VERSION, DELETE, 3, 1, 2, 0, 0, 0, 0]).unwrap_err(),
+            Error::MalformedClock { site_id: 3, len: 1 }
+        );
+
+        // a short message can't make the receiver expand ranges without limit
+        let mut writer = Writer {
+            bytes: vec![VERSION],
+            clock: Vec::new(),
+        };
+        let delete = Operation {
+            sent_by: 0,
+            op_clock: vec![u32::MAX - 1],
+            data: OperationData::Delete([0; 4]),
+        };
+        for _ in 0..2 {
+            writer.header(DELETE_RANGE, &delete);
+            writer.varint(1);
+            writer.position([0, 0, 1, 1]);
+            writer.varint(MAX_POSITIONS as u64 / 2 + 1);
+        }
+        assert_eq!(
+            decode_operations(&writer.bytes).unwrap_err(),
+            Error::InvalidEncoding
+        );
+
+        // typed text must not count past the end of the clock
+        let mut writer = Writer {
+            bytes: vec![VERSION],
+            clock: Vec::new(),
+        };
+        let insert = Operation {
+            op_clock: vec![u32::MAX],
+            ..delete
+        };
+        writer.header(INSERT_TEXT, &insert);
+        writer.position([0; 4]);
+        writer.text("ab");
+        assert_eq!(
+            decode_operations(&writer.bytes).unwrap_err(),
+            Error::InvalidEncoding
+        );
+
+        // clock deltas can't overflow the previous clock
+        let mut writer = Writer {
+            bytes: vec![VERSION],
+            clock: Vec::new(),
+        };
+        let first = Operation {
+            sent_by: 0,
+            op_clock: vec![1],
+            data: OperationData::Delete([0; 4]),
+        };
+        writer.header(DELETE, &first);
+        writer.position([0; 4]);
+        writer.bytes.extend([DELETE, 0, 1]);
+        writer.varint(zigzag(i64::MAX));
+        writer.position([0; 4]);
+        assert_eq!(
+            decode_operations(&writer.bytes).unwrap_err(),
+            Error::InvalidEncoding
+        );
+
+        // malformed clocks and full entries don't start runs of typed text
+        let malformed = Operation {
+            sent_by: 3,
+            op_clock: vec![1],
+            data: OperationData::Insert(InsertOperation {
+                value: 'a',
+                insert_after: [0; 4],
+                insert_position: [0; 4],
+            }),
+        };
+        let position = [0, 0, u32::MAX, u32::MAX];
+        let full = Operation {
+            sent_by: 0,
+            op_clock: vec![u32::MAX],
+            data: OperationData::Insert(InsertOperation {
+                value: 'a',
+                insert_after: position,
+                insert_position: position,
+            }),
+        };
+        assert_eq!(implied_position(&full), position);
+        for operation in [malformed, full] {
+            encode_operations(&[operation.clone(), operation]);
+        }
+    }
+}
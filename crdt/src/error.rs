@@ -0,0 +1,89 @@
+use std::fmt;
+
+use crate::clocks::S4Vector;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The operation depends on operations of `site` that were not received yet.
+    CausalityGap {
+        site: usize,
+        received: u32,
+        required: u32,
+    },
+    /// The operation references a position that is not part of the sequence.
+    UnknownPosition(S4Vector),
+    /// An element with this position was inserted before.
+    DuplicatePosition(S4Vector),
+    /// The clock has no entry for the site that sent it, or the positions it stamps overflow.
+    MalformedClock { site_id: usize, len: usize },
+    /// The operation was integrated before.
+    DuplicateOperation { sent_by: usize, sequence: u32 },
+    /// The index lies beyond the end of the visible sequence.
+    IndexOutOfBounds { index: usize, len: usize },
+    /// The snapshot has no root or contains elements that aren't linked into the sequence.
+    MalformedSnapshot,
+    /// Operations that weren't received as operations are requested, e.g. because they were
+    /// part of a snapshot or merged replica.
+    MissingHistory,
+    /// The bytes aren't a valid encoding of operations.
+    InvalidEncoding,
+    /// The operations were encoded with a format version this build doesn't know.
+    UnsupportedEncodingVersion(u8),
+    /// The path doesn't lead to a value of the kind the operation needs.
+    InvalidPath,
+    /// The node would be moved below itself.
+    CyclicMove(S4Vector),
+    /// Every site has seen the value deleted, so it may have been collected already.
+    StableDeletion(S4Vector),
+    /// An insert run without values.
+    EmptyRun,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CausalityGap {
+                site,
+                received,
+                required,
+            } => write!(
+                f,
+                "missing operations of site {}: received {} but {} are required",
+                site, received, required
+            ),
+            Error::UnknownPosition(pos) => write!(f, "unknown position {:?}", pos.to_array()),
+            Error::DuplicatePosition(pos) => {
+                write!(f, "position {:?} already exists", pos.to_array())
+            }
+            Error::MalformedClock { site_id, len } => {
+                write!(f, "malformed clock of length {} for site {}", len, site_id)
+            }
+            Error::DuplicateOperation { sent_by, sequence } => write!(
+                f,
+                "operation {} of site {} was already applied",
+                sequence, sent_by
+            ),
+            Error::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} is out of bounds for length {}", index, len)
+            }
+            Error::MalformedSnapshot => write!(f, "the snapshot isn't a single linked sequence"),
+            Error::MissingHistory => write!(f, "the requested operations are no longer known"),
+            Error::InvalidEncoding => write!(f, "invalid encoding of operations"),
+            Error::UnsupportedEncodingVersion(version) => {
+                write!(f, "unsupported encoding version {}", version)
+            }
+            Error::InvalidPath => write!(f, "the path doesn't lead to a matching value"),
+            Error::CyclicMove(node) => {
+                write!(f, "node {:?} can't be moved below itself", node.to_array())
+            }
+            Error::StableDeletion(pos) => write!(
+                f,
+                "the deletion of {:?} is stable and may be collected already",
+                pos.to_array()
+            ),
+            Error::EmptyRun => write!(f, "the insert run has no values"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
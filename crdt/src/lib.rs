@@ -1,3 +1,18 @@
+pub mod awareness;
+mod causal;
 pub mod clocks;
+pub mod counter;
+pub mod cursor;
 pub mod data_structure;
+pub mod document;
+pub mod encoding;
+pub mod error;
+pub mod lww;
+pub mod marks;
+pub mod or_set;
+mod order_index;
 pub mod rga;
+pub mod tree;
+pub mod undo;
+
+pub use error::Error;
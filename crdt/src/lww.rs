@@ -0,0 +1,338 @@
+//! Last-writer-wins register and map. Concurrent writes are ordered by the [`S4Vector`] of
+//! the operations, the same order [`crate::rga::RGA`] uses for concurrent updates.
+
+use std::{collections::HashMap, hash::Hash};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    causal::{deliver, CausalBuffer, CausalOperation, CausalReplica},
+    clocks::{S4Vector, VectorClock},
+    data_structure::ApplyStatus,
+    Error,
+};
+
+/// Assigns `value` to the register, `None` clears it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterOperation<T> {
+    pub sent_by: usize,
+    pub op_clock: Vec<u32>,
+    pub value: Option<T>,
+}
+
+impl<T> CausalOperation for RegisterOperation<T> {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    fn first_clock(&self) -> Vec<u32> {
+        self.op_clock.clone()
+    }
+}
+
+// a value and the timestamp of the write that assigned it
+#[derive(Debug, Clone)]
+pub(crate) struct Slot<T> {
+    pub value: Option<T>,
+    pub timestamp: S4Vector,
+}
+
+impl<T> Slot<T> {
+    pub fn empty() -> Slot<T> {
+        Slot {
+            value: None,
+            timestamp: S4Vector::root(),
+        }
+    }
+
+    pub fn assign(&mut self, value: Option<T>, timestamp: S4Vector) {
+        if timestamp > self.timestamp {
+            self.value = value;
+            self.timestamp = timestamp;
+        }
+    }
+}
+
+/// A single value several sites assign concurrently.
+pub struct LwwRegister<T> {
+    clock: VectorClock,
+    slot: Slot<T>,
+    pending: CausalBuffer<RegisterOperation<T>>,
+}
+
+impl<T: Clone> LwwRegister<T> {
+    pub fn new(id: usize) -> LwwRegister<T> {
+        LwwRegister {
+            clock: VectorClock::new(id),
+            slot: Slot::empty(),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.slot.value.as_ref()
+    }
+
+    pub fn local_set(&mut self, value: Option<T>) -> RegisterOperation<T> {
+        self.clock.increase();
+        self.remote_set(self.clock.to_s4vector(), value.clone());
+        RegisterOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            value,
+        }
+    }
+
+    pub fn remote_set(&mut self, operation_ts: S4Vector, value: Option<T>) {
+        self.slot.assign(value, operation_ts);
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[RegisterOperation<T>] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(RegisterOperation<T>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Delivers `operation` like [`SynchronizedSequence::apply_operation`].
+    ///
+    /// [`SynchronizedSequence::apply_operation`]: crate::data_structure::SynchronizedSequence::apply_operation
+    pub fn apply_operation(
+        &mut self,
+        operation: &RegisterOperation<T>,
+    ) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+}
+
+impl<T: Clone> CausalReplica for LwwRegister<T> {
+    type Operation = RegisterOperation<T>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<RegisterOperation<T>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &RegisterOperation<T>) -> Result<(), Error> {
+        let clock = VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?;
+        self.remote_set(clock.to_s4vector(), operation.value.clone());
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MapOperationData<K, V> {
+    Set { key: K, value: V },
+    Remove(K),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MapOperation<K, V> {
+    pub sent_by: usize,
+    pub op_clock: Vec<u32>,
+    pub data: MapOperationData<K, V>,
+}
+
+impl<K, V> CausalOperation for MapOperation<K, V> {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    fn first_clock(&self) -> Vec<u32> {
+        self.op_clock.clone()
+    }
+}
+
+/// A map whose keys are last-writer-wins registers. Removed keys keep the timestamp of their
+/// removal, so an older concurrent write doesn't bring them back.
+pub struct LwwMap<K, V> {
+    clock: VectorClock,
+    entries: HashMap<K, Slot<V>>,
+    pending: CausalBuffer<MapOperation<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LwwMap<K, V> {
+    pub fn new(id: usize) -> LwwMap<K, V> {
+        LwwMap {
+            clock: VectorClock::new(id),
+            entries: HashMap::new(),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.value.as_ref()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The keys that are not removed with their values, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, slot)| Some((key, slot.value.as_ref()?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn local_set(&mut self, key: K, value: V) -> MapOperation<K, V> {
+        self.local_operation(MapOperationData::Set { key, value })
+    }
+
+    pub fn local_remove(&mut self, key: K) -> MapOperation<K, V> {
+        self.local_operation(MapOperationData::Remove(key))
+    }
+
+    pub fn remote_set(&mut self, operation_ts: S4Vector, key: K, value: Option<V>) {
+        self.entries
+            .entry(key)
+            .or_insert_with(Slot::empty)
+            .assign(value, operation_ts);
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[MapOperation<K, V>] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(MapOperation<K, V>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Delivers `operation` like [`SynchronizedSequence::apply_operation`].
+    ///
+    /// [`SynchronizedSequence::apply_operation`]: crate::data_structure::SynchronizedSequence::apply_operation
+    pub fn apply_operation(
+        &mut self,
+        operation: &MapOperation<K, V>,
+    ) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+
+    fn local_operation(&mut self, data: MapOperationData<K, V>) -> MapOperation<K, V> {
+        self.clock.increase();
+        self.write(self.clock.to_s4vector(), &data);
+        MapOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data,
+        }
+    }
+
+    fn write(&mut self, operation_ts: S4Vector, data: &MapOperationData<K, V>) {
+        match data {
+            MapOperationData::Set { key, value } => {
+                self.remote_set(operation_ts, key.clone(), Some(value.clone()))
+            }
+            MapOperationData::Remove(key) => self.remote_set(operation_ts, key.clone(), None),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> CausalReplica for LwwMap<K, V> {
+    type Operation = MapOperation<K, V>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<MapOperation<K, V>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &MapOperation<K, V>) -> Result<(), Error> {
+        let clock = VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?;
+        self.write(clock.to_s4vector(), &operation.data);
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let mut title0 = LwwRegister::new(0);
+        let mut title1 = LwwRegister::new(1);
+        let first = title0.local_set(Some("Draft".to_owned()));
+        title1.apply_operation(&first).unwrap();
+
+        // concurrent writes, the one of site 1 has the larger timestamp
+        let write0 = title0.local_set(Some("Notes".to_owned()));
+        let write1 = title1.local_set(Some("Minutes".to_owned()));
+        assert_eq!(title0.apply_operation(&write1), Ok(ApplyStatus::Applied));
+        assert_eq!(title1.apply_operation(&write0), Ok(ApplyStatus::Applied));
+        assert_eq!(title0.get().unwrap(), "Minutes");
+        assert_eq!(title1.get().unwrap(), "Minutes");
+        assert_eq!(
+            title1.apply_operation(&write0),
+            Ok(ApplyStatus::AlreadyApplied)
+        );
+
+        let clear = title0.local_set(None);
+        title1.apply_operation(&clear).unwrap();
+        assert_eq!(title1.get(), None);
+    }
+
+    #[test]
+    fn test_map() {
+        let mut meta0 = LwwMap::new(0);
+        let mut meta1 = LwwMap::new(1);
+        let mut meta2 = LwwMap::new(2);
+        let ops0 = vec![
+            meta0.local_set("title", "Minutes".to_owned()),
+            meta0.local_set("language", "en".to_owned()),
+        ];
+        for op in &ops0 {
+            meta1.apply_operation(op).unwrap();
+        }
+
+        // site 1 removes the language after seeing it, site 0 concurrently changes the owner
+        let ops1 = vec![
+            meta1.local_remove("language"),
+            meta1.local_set("owner", "ada".to_owned()),
+        ];
+        let owner = meta0.local_set("owner", "grace".to_owned());
+        for op in &ops1 {
+            meta0.apply_operation(op).unwrap();
+        }
+        meta1.apply_operation(&owner).unwrap();
+
+        // operations arriving before the ones they depend on are buffered
+        for op in ops1.iter().chain([&owner]).chain(&ops0) {
+            meta2.apply_operation(op).unwrap();
+        }
+        assert!(meta2.pending_operations().is_empty());
+
+        for meta in [&meta0, &meta1, &meta2] {
+            assert_eq!(meta.get(&"title").unwrap(), "Minutes");
+            assert!(!meta.contains_key(&"language"));
+            assert_eq!(meta.get(&"owner").unwrap(), "ada");
+            assert_eq!(meta.len(), 2);
+        }
+    }
+}
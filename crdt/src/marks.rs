@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clocks::S4Vector, rga::RGA};
+
+/// A gap between two elements, marks start and end in gaps instead of at elements.
+///
+/// Marks that end `Before` the element following them grow when text is typed at their end,
+/// marks that end `After` their last element don't.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    Before([u32; 4]),
+    After([u32; 4]),
+    End,
+}
+
+impl Anchor {
+    pub fn position(&self) -> Option<S4Vector> {
+        match self {
+            Anchor::Before(pos) | Anchor::After(pos) => Some((*pos).into()),
+            Anchor::End => None,
+        }
+    }
+}
+
+/// Formatting `name` with an optional `value`, e.g. the target of a link, between `start`
+/// and `end`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MarkOperation {
+    pub name: String,
+    pub value: Option<String>,
+    pub start: Anchor,
+    pub end: Anchor,
+}
+
+/// A mark added or removed by an operation with `timestamp`. For every element the latest
+/// mark of a name covering it decides whether the element has that formatting.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mark {
+    pub timestamp: [u32; 4],
+    pub removed: bool,
+    pub mark: MarkOperation,
+}
+
+/// Consecutive text with the same formatting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormattedRun {
+    pub text: String,
+    pub marks: BTreeMap<String, Option<String>>,
+}
+
+// Gaps and elements in sequence order: the gap before the `n`-th element is `(n, 0)`, the
+// element itself `(n, 1)` and the gap behind it `(n, 2)`.
+type Key = (usize, u8);
+
+pub(crate) fn formatted_runs(rga: &RGA<char>, marks: &[Mark]) -> Vec<FormattedRun> {
+    let ordinals: HashMap<S4Vector, usize> = rga
+        .iter()
+        .enumerate()
+        .map(|(ordinal, (pos, _))| (pos, ordinal))
+        .collect();
+    let key = |anchor: &Anchor| -> Option<Key> {
+        match anchor {
+            Anchor::Before(pos) => Some((*ordinals.get(&(*pos).into())?, 0)),
+            Anchor::After(pos) => Some((*ordinals.get(&(*pos).into())?, 2)),
+            Anchor::End => Some((usize::MAX, 0)),
+        }
+    };
+    let mut marks: Vec<(Key, Key, &Mark)> = marks
+        .iter()
+        .filter_map(|mark| Some((key(&mark.mark.start)?, key(&mark.mark.end)?, mark)))
+        .collect();
+    marks.sort_by_key(|(_, _, mark)| S4Vector::from(mark.timestamp));
+    let mut boundaries: Vec<Key> = marks
+        .iter()
+        .flat_map(|(start, end, _)| [*start, *end])
+        .collect();
+    boundaries.sort();
+
+    // the formatting only changes when a boundary is passed
+    let mut passed = 0;
+    let mut formatting = BTreeMap::new();
+    let mut runs: Vec<FormattedRun> = Vec::new();
+    for (ordinal, (_, character)) in rga.iter().enumerate() {
+        let Some(character) = character else {
+            continue;
+        };
+        let key = (ordinal, 1);
+        let before = passed;
+        while passed < boundaries.len() && boundaries[passed] < key {
+            passed += 1;
+        }
+        if passed != before {
+            formatting = BTreeMap::new();
+            for (_, _, mark) in marks
+                .iter()
+                .filter(|(start, end, _)| *start < key && key < *end)
+            {
+                if mark.removed {
+                    formatting.remove(&mark.mark.name);
+                } else {
+                    formatting.insert(mark.mark.name.clone(), mark.mark.value.clone());
+                }
+            }
+        }
+        match runs.last_mut() {
+            Some(run) if run.marks == formatting => run.text.push(character),
+            _ => runs.push(FormattedRun {
+                text: character.to_string(),
+                marks: formatting.clone(),
+            }),
+        }
+    }
+    runs
+}
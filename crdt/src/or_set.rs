@@ -0,0 +1,208 @@
+//! Observed-remove set. Every add tags the value with the [`S4Vector`] of its operation and a
+//! remove only removes the tags its site had observed, so an add concurrent to a remove wins.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    causal::{deliver, CausalBuffer, CausalOperation, CausalReplica},
+    clocks::{S4Vector, VectorClock},
+    data_structure::ApplyStatus,
+    Error,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SetOperationData<T> {
+    Add(T),
+    /// Removes the adds of `value` with these tags.
+    Remove {
+        value: T,
+        tags: Vec<[u32; 4]>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetOperation<T> {
+    pub sent_by: usize,
+    pub op_clock: Vec<u32>,
+    pub data: SetOperationData<T>,
+}
+
+impl<T> CausalOperation for SetOperation<T> {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    fn first_clock(&self) -> Vec<u32> {
+        self.op_clock.clone()
+    }
+}
+
+pub struct ORSet<T> {
+    clock: VectorClock,
+    // tags of the adds that weren't removed yet, values without tags are dropped
+    elements: HashMap<T, HashSet<S4Vector>>,
+    pending: CausalBuffer<SetOperation<T>>,
+}
+
+impl<T: Eq + Hash + Clone> ORSet<T> {
+    pub fn new(id: usize) -> ORSet<T> {
+        ORSet {
+            clock: VectorClock::new(id),
+            elements: HashMap::new(),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains_key(value)
+    }
+
+    /// The values of the set in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn local_add(&mut self, value: T) -> SetOperation<T> {
+        self.clock.increase();
+        self.remote_add(self.clock.to_s4vector(), value.clone());
+        self.operation(SetOperationData::Add(value))
+    }
+
+    /// Removes `value` as far as this site has seen it, `None` if it isn't part of the set.
+    pub fn local_remove(&mut self, value: T) -> Option<SetOperation<T>> {
+        let tags: Vec<S4Vector> = self.elements.get(&value)?.iter().copied().collect();
+        self.clock.increase();
+        self.remote_remove(&value, &tags);
+        Some(self.operation(SetOperationData::Remove {
+            value,
+            tags: tags.iter().map(S4Vector::to_array).collect(),
+        }))
+    }
+
+    pub fn remote_add(&mut self, operation_ts: S4Vector, value: T) {
+        self.elements.entry(value).or_default().insert(operation_ts);
+    }
+
+    pub fn remote_remove(&mut self, value: &T, tags: &[S4Vector]) {
+        let Some(live) = self.elements.get_mut(value) else {
+            return;
+        };
+        for tag in tags {
+            live.remove(tag);
+        }
+        if live.is_empty() {
+            self.elements.remove(value);
+        }
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[SetOperation<T>] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(SetOperation<T>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Delivers `operation` like [`SynchronizedSequence::apply_operation`]. Causal delivery
+    /// guarantees that the adds a remove observed are applied before it.
+    ///
+    /// [`SynchronizedSequence::apply_operation`]: crate::data_structure::SynchronizedSequence::apply_operation
+    pub fn apply_operation(&mut self, operation: &SetOperation<T>) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+
+    fn operation(&self, data: SetOperationData<T>) -> SetOperation<T> {
+        SetOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data,
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> CausalReplica for ORSet<T> {
+    type Operation = SetOperation<T>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<SetOperation<T>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &SetOperation<T>) -> Result<(), Error> {
+        let clock = VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?;
+        match &operation.data {
+            SetOperationData::Add(value) => self.remote_add(clock.to_s4vector(), value.clone()),
+            SetOperationData::Remove { value, tags } => {
+                let tags: Vec<S4Vector> = tags.iter().map(|tag| (*tag).into()).collect();
+                self.remote_remove(value, &tags)
+            }
+        }
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_wins() {
+        let mut tags0 = ORSet::new(0);
+        let mut tags1 = ORSet::new(1);
+        let ops = [tags0.local_add("draft"), tags0.local_add("urgent")];
+        for op in &ops {
+            tags1.apply_operation(op).unwrap();
+        }
+
+        // site 1 adds "draft" again while site 0 removes the add it has seen
+        let remove = tags0.local_remove("draft").unwrap();
+        let add = tags1.local_add("draft");
+        let remove_urgent = tags1.local_remove("urgent").unwrap();
+        assert!(tags0.local_remove("missing").is_none());
+
+        tags0.apply_operation(&add).unwrap();
+        tags0.apply_operation(&remove_urgent).unwrap();
+        tags1.apply_operation(&remove).unwrap();
+        for tags in [&tags0, &tags1] {
+            assert!(tags.contains(&"draft"));
+            assert!(!tags.contains(&"urgent"));
+            assert_eq!(tags.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_delivery() {
+        let mut set0 = ORSet::new(0);
+        let mut set1 = ORSet::new(1);
+        let add = set0.local_add(1);
+        let remove = set0.local_remove(1).unwrap();
+
+        // the remove waits for the add it observed
+        assert_eq!(set1.apply_operation(&remove), Ok(ApplyStatus::Buffered));
+        assert_eq!(set1.apply_operation(&add), Ok(ApplyStatus::Applied));
+        assert!(set1.pending_operations().is_empty());
+        assert!(set1.is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+
+use crate::clocks::S4Vector;
+
+/// Treap over the positions of an [`RGA`](crate::rga::RGA) in list order. Every slot knows
+/// how many visible elements its subtree contains, which turns index lookups into walks
+/// along a single path of the tree.
+pub(crate) struct OrderIndex {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    root: usize,
+    handles: HashMap<S4Vector, usize>,
+}
+
+struct Slot {
+    position: S4Vector,
+    priority: u64,
+    parent: Option<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+    visible: bool,
+    count: usize,
+}
+
+impl OrderIndex {
+    pub(crate) fn new(first: S4Vector) -> OrderIndex {
+        let mut handles = HashMap::new();
+        handles.insert(first, 0);
+        OrderIndex {
+            slots: vec![Slot::new(first, false, None)],
+            free: Vec::new(),
+            root: 0,
+            handles,
+        }
+    }
+
+    pub(crate) fn contains(&self, position: S4Vector) -> bool {
+        self.handles.contains_key(&position)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots[self.root].count
+    }
+
+    /// Adds `position` directly behind `after` in list order.
+    pub(crate) fn insert_after(&mut self, after: S4Vector, position: S4Vector, visible: bool) {
+        let after = self.handles[&after];
+        let (parent, is_left) = match self.slots[after].right {
+            None => (after, false),
+            Some(mut node) => {
+                while let Some(left) = self.slots[node].left {
+                    node = left;
+                }
+                (node, true)
+            }
+        };
+
+        let slot = Slot::new(position, visible, Some(parent));
+        let node = match self.free.pop() {
+            Some(node) => {
+                self.slots[node] = slot;
+                node
+            }
+            None => {
+                self.slots.push(slot);
+                self.slots.len() - 1
+            }
+        };
+        if is_left {
+            self.slots[parent].left = Some(node);
+        } else {
+            self.slots[parent].right = Some(node);
+        }
+        self.handles.insert(position, node);
+        self.add_to_ancestors(node, visible as usize, 0);
+
+        while let Some(parent) = self.slots[node].parent {
+            if self.slots[parent].priority >= self.slots[node].priority {
+                break;
+            }
+            self.rotate_up(node);
+        }
+    }
+
+    pub(crate) fn set_visible(&mut self, position: S4Vector, visible: bool) {
+        let node = self.handles[&position];
+        if self.slots[node].visible == visible {
+            return;
+        }
+        self.slots[node].visible = visible;
+        if visible {
+            self.slots[node].count += 1;
+            self.add_to_ancestors(node, 1, 0);
+        } else {
+            self.slots[node].count -= 1;
+            self.add_to_ancestors(node, 0, 1);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, position: S4Vector) {
+        let Some(node) = self.handles.remove(&position) else {
+            return;
+        };
+        // rotate the slot down until it is a leaf, then cut it off
+        loop {
+            let child = match (self.slots[node].left, self.slots[node].right) {
+                (None, None) => break,
+                (Some(child), None) | (None, Some(child)) => child,
+                (Some(left), Some(right)) => {
+                    if self.slots[left].priority > self.slots[right].priority {
+                        left
+                    } else {
+                        right
+                    }
+                }
+            };
+            self.rotate_up(child);
+        }
+        if self.slots[node].visible {
+            self.add_to_ancestors(node, 0, 1);
+        }
+        if let Some(parent) = self.slots[node].parent {
+            if self.slots[parent].left == Some(node) {
+                self.slots[parent].left = None;
+            } else {
+                self.slots[parent].right = None;
+            }
+        }
+        self.free.push(node);
+    }
+
+    /// Number of visible elements up to and including `position`.
+    pub(crate) fn rank(&self, position: S4Vector) -> Option<usize> {
+        let mut node = *self.handles.get(&position)?;
+        let mut rank = self.count(self.slots[node].left) + self.slots[node].visible as usize;
+        while let Some(parent) = self.slots[node].parent {
+            if self.slots[parent].right == Some(node) {
+                rank += self.count(self.slots[parent].left) + self.slots[parent].visible as usize;
+            }
+            node = parent;
+        }
+        Some(rank)
+    }
+
+    /// The `rank`-th visible element, counting from one.
+    pub(crate) fn select(&self, mut rank: usize) -> Option<S4Vector> {
+        if rank == 0 || rank > self.len() {
+            return None;
+        }
+        let mut node = self.root;
+        loop {
+            let slot = &self.slots[node];
+            let left_count = self.count(slot.left);
+            if rank <= left_count {
+                node = slot.left?;
+            } else if slot.visible && rank == left_count + 1 {
+                return Some(slot.position);
+            } else {
+                rank -= left_count + slot.visible as usize;
+                node = slot.right?;
+            }
+        }
+    }
+
+    fn count(&self, node: Option<usize>) -> usize {
+        node.map_or(0, |node| self.slots[node].count)
+    }
+
+    fn add_to_ancestors(&mut self, mut node: usize, added: usize, removed: usize) {
+        while let Some(parent) = self.slots[node].parent {
+            self.slots[parent].count = self.slots[parent].count + added - removed;
+            node = parent;
+        }
+    }
+
+    fn rotate_up(&mut self, node: usize) {
+        let parent = self.slots[node]
+            .parent
+            .expect("the root can't be rotated up");
+        let grand_parent = self.slots[parent].parent;
+
+        if self.slots[parent].left == Some(node) {
+            let moved = self.slots[node].right;
+            self.slots[parent].left = moved;
+            self.slots[node].right = Some(parent);
+            if let Some(moved) = moved {
+                self.slots[moved].parent = Some(parent);
+            }
+        } else {
+            let moved = self.slots[node].left;
+            self.slots[parent].right = moved;
+            self.slots[node].left = Some(parent);
+            if let Some(moved) = moved {
+                self.slots[moved].parent = Some(parent);
+            }
+        }
+
+        self.slots[parent].parent = Some(node);
+        self.slots[node].parent = grand_parent;
+        match grand_parent {
+            None => self.root = node,
+            Some(grand_parent) => {
+                if self.slots[grand_parent].left == Some(parent) {
+                    self.slots[grand_parent].left = Some(node);
+                } else {
+                    self.slots[grand_parent].right = Some(node);
+                }
+            }
+        }
+
+        self.update_count(parent);
+        self.update_count(node);
+    }
+
+    fn update_count(&mut self, node: usize) {
+        let slot = &self.slots[node];
+        let count = self.count(slot.left) + self.count(slot.right) + slot.visible as usize;
+        self.slots[node].count = count;
+    }
+}
+
+impl Slot {
+    fn new(position: S4Vector, visible: bool, parent: Option<usize>) -> Slot {
+        Slot {
+            position,
+            priority: priority(position),
+            parent,
+            left: None,
+            right: None,
+            visible,
+            count: visible as usize,
+        }
+    }
+}
+
+// positions are unique, so mixing their parts gives every slot a well spread, deterministic priority
+fn priority(position: S4Vector) -> u64 {
+    let mut x = ((position.sid as u64) << 32 | position.seq as u64) ^ (position.sum as u64) << 17;
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[test]
+fn test_matches_list_model() {
+    let mut index = OrderIndex::new(S4Vector::root());
+    let mut model = vec![(S4Vector::root(), false)];
+    let mut seed: u32 = 7;
+    let mut next = || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (seed >> 8) as usize
+    };
+
+    for seq in 1..500 {
+        let position = S4Vector::from([0, 1, seq, seq]);
+        let after = next() % model.len();
+        index.insert_after(model[after].0, position, true);
+        model.insert(after + 1, (position, true));
+
+        let toggled = next() % model.len();
+        if toggled != 0 && next() % 3 == 0 {
+            model[toggled].1 = !model[toggled].1;
+            index.set_visible(model[toggled].0, model[toggled].1);
+        }
+        if next() % 7 == 0 {
+            let removed = 1 + next() % (model.len() - 1);
+            index.remove(model.remove(removed).0);
+        }
+    }
+
+    let visible: Vec<S4Vector> = model.iter().filter(|(_, v)| *v).map(|(p, _)| *p).collect();
+    assert_eq!(index.len(), visible.len());
+    for (rank, position) in visible.iter().enumerate() {
+        assert_eq!(index.select(rank + 1), Some(*position));
+    }
+    let mut rank = 0;
+    for (position, v) in &model {
+        rank += *v as usize;
+        assert_eq!(index.rank(*position), Some(rank));
+    }
+    assert_eq!(index.select(visible.len() + 1), None);
+}
@@ -1,25 +1,117 @@
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
+
 use super::clocks::S4Vector;
+use crate::order_index::OrderIndex;
+use crate::Error;
 
 pub struct RGA<T> {
     nodes: HashMap<S4Vector, Node<T>>,
+    index: OrderIndex,
+    // slots moves created for an element, the one with the largest position holds the element
+    moves: HashMap<S4Vector, Vec<S4Vector>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeSnapshot<T> {
+    pub position: [u32; 4],
+    pub object: Option<T>,
+    pub update_clock: [u32; 4],
+    /// Deleted elements keep their object until they are collected.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Clock of the last update of `object`, `update_clock` if missing.
+    #[serde(default)]
+    pub value_clock: Option<[u32; 4]>,
+    pub origin: [u32; 4],
+    pub link: Option<[u32; 4]>,
+    /// The element a move placed at this position, `None` for inserted elements.
+    #[serde(default)]
+    pub element: Option<[u32; 4]>,
+}
+
+impl<T: Clone> Default for RGA<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<T: Clone + Default> RGA<T> {
+impl<T: Clone> RGA<T> {
     pub fn new() -> RGA<T> {
         let mut nodes = HashMap::new();
         let n = Node {
-            object: None,
-            update_clock: S4Vector::root(),
+            state: State::empty(S4Vector::root()),
+            origin: S4Vector::root(),
             link: None,
+            element: None,
         };
         nodes.insert(S4Vector::root(), n);
-        RGA { nodes }
+        RGA {
+            nodes,
+            index: OrderIndex::new(S4Vector::root()),
+            moves: HashMap::new(),
+        }
+    }
+    pub fn contains(&self, position: &S4Vector) -> bool {
+        self.nodes.contains_key(position)
+    }
+
+    /// The object at `position`, `None` if it is deleted or empty.
+    pub fn get(&self, position: S4Vector) -> Option<&T> {
+        self.nodes.get(&position)?.state.visible()
+    }
+
+    pub fn insert(
+        &mut self,
+        insert_after: S4Vector,
+        operation_clock: S4Vector,
+        object: T,
+    ) -> Result<(), Error> {
+        self.integrate(
+            insert_after,
+            operation_clock,
+            State::new(object, operation_clock),
+        )
+    }
+
+    /// Inserts `objects` one after another behind `insert_after`, at consecutive positions
+    /// starting with `first_position`. Either all of them are inserted or none.
+    pub fn insert_run(
+        &mut self,
+        insert_after: S4Vector,
+        first_position: S4Vector,
+        objects: Vec<T>,
+    ) -> Result<(), Error> {
+        if !self.nodes.contains_key(&insert_after) {
+            return Err(Error::UnknownPosition(insert_after));
+        }
+        if let Some(duplicate) = (0..objects.len() as u32)
+            .map(|offset| first_position.run_position(offset))
+            .find(|pos| self.nodes.contains_key(pos))
+        {
+            return Err(Error::DuplicatePosition(duplicate));
+        }
+        let mut insert_after = insert_after;
+        for (offset, object) in objects.into_iter().enumerate() {
+            let position = first_position.run_position(offset as u32);
+            self.integrate(insert_after, position, State::new(object, position))?;
+            insert_after = position;
+        }
+        Ok(())
     }
-    pub fn insert(&mut self, insert_after: S4Vector, operation_clock: S4Vector, object: T) -> bool {
+
+    fn integrate(
+        &mut self,
+        insert_after: S4Vector,
+        operation_clock: S4Vector,
+        state: State<T>,
+    ) -> Result<(), Error> {
         if !self.nodes.contains_key(&insert_after) {
-            return false;
+            return Err(Error::UnknownPosition(insert_after));
+        }
+        if self.nodes.contains_key(&operation_clock) {
+            return Err(Error::DuplicatePosition(operation_clock));
         }
         let mut ref_pos = insert_after;
         while let Some(link) = self.nodes[&ref_pos].link {
@@ -32,25 +124,297 @@ impl<T: Clone + Default> RGA<T> {
         let reference = self.nodes.get_mut(&ref_pos).unwrap();
         let link = reference.link;
         reference.link = Some(operation_clock);
+        self.index
+            .insert_after(ref_pos, operation_clock, state.visible().is_some());
 
         self.nodes.insert(
             operation_clock,
             Node {
-                object: Some(object),
-                update_clock: operation_clock,
+                state,
+                origin: insert_after,
                 link,
+                element: None,
             },
         );
-        true
+        Ok(())
+    }
+
+    /// Slot that currently holds the element at `position`, elements are identified by the
+    /// position they were inserted at and may have been moved since.
+    pub fn resolve(&self, position: S4Vector) -> Option<S4Vector> {
+        let node = self.nodes.get(&position)?;
+        Some(self.current_slot(node.element.unwrap_or(position)))
     }
 
-    pub fn delete(&mut self, element: S4Vector, operation_ts: S4Vector) -> bool {
-        if let Some(el) = self.nodes.get_mut(&element) {
-            el.object = None;
+    fn current_slot(&self, element: S4Vector) -> S4Vector {
+        self.moves
+            .get(&element)
+            .and_then(|slots| slots.iter().max())
+            .copied()
+            .unwrap_or(element)
+    }
+
+    /// The element in the slot at `position`, the position it was inserted at.
+    pub(crate) fn element_of(&self, position: S4Vector) -> S4Vector {
+        self.nodes
+            .get(&position)
+            .and_then(|node| node.element)
+            .unwrap_or(position)
+    }
+
+    /// Moves the element at `position` behind `insert_after` into a new slot at `operation_ts`.
+    /// Of concurrent moves of the same element the one with the largest timestamp decides
+    /// where it ends up, the slots of the other moves stay empty.
+    pub fn move_to(
+        &mut self,
+        position: S4Vector,
+        insert_after: S4Vector,
+        operation_ts: S4Vector,
+    ) -> Result<(), Error> {
+        if !self.nodes.contains_key(&position) {
+            return Err(Error::UnknownPosition(position));
+        }
+        let element = self.element_of(position);
+        let from = self.current_slot(element);
+        self.integrate(insert_after, operation_ts, State::empty(operation_ts))?;
+        self.nodes.get_mut(&operation_ts).unwrap().element = Some(element);
+        self.moves.entry(element).or_default().push(operation_ts);
+
+        let state = self
+            .nodes
+            .get_mut(&from)
+            .map(|node| std::mem::replace(&mut node.state, State::empty(operation_ts)));
+        if let Some(state) = state {
+            self.settle(element, state);
+        }
+        Ok(())
+    }
+
+    // Puts the object of `element` and its clocks into the current slot. Every other slot of
+    // the element is empty and stamped with the move to the current slot, so replicas agree on
+    // them no matter in which order the moves arrived.
+    fn settle(&mut self, element: S4Vector, state: State<T>) {
+        let current = self.current_slot(element);
+        let slots = self.moves.get(&element).into_iter().flatten().copied();
+        for slot in std::iter::once(element).chain(slots) {
+            let Some(node) = self.nodes.get_mut(&slot) else {
+                continue;
+            };
+            if slot != current {
+                node.state = State::empty(current);
+                self.index.set_visible(slot, false);
+            }
+        }
+        if let Some(node) = self.nodes.get_mut(&current) {
+            self.index.set_visible(current, state.visible().is_some());
+            node.state = state;
+        }
+    }
+
+    /// Deletes the element at `position` but keeps its object, so it can be restored.
+    pub fn delete(&mut self, position: S4Vector, operation_ts: S4Vector) -> Result<(), Error> {
+        self.set_deleted(position, true, operation_ts)
+    }
+
+    /// Brings back the deleted element at `position` with its identity. Of concurrent deletes
+    /// and restores of an element the one with the largest timestamp wins.
+    pub fn restore(&mut self, position: S4Vector, operation_ts: S4Vector) -> Result<(), Error> {
+        self.set_deleted(position, false, operation_ts)
+    }
+
+    fn set_deleted(
+        &mut self,
+        position: S4Vector,
+        deleted: bool,
+        operation_ts: S4Vector,
+    ) -> Result<(), Error> {
+        let element = self
+            .resolve(position)
+            .ok_or(Error::UnknownPosition(position))?;
+        let el = &mut self.nodes.get_mut(&element).unwrap().state;
+        if operation_ts > el.update_clock {
+            el.deleted = deleted;
             el.update_clock = operation_ts;
-            return true;
         }
-        false
+        self.index.set_visible(element, el.visible().is_some());
+        Ok(())
+    }
+
+    /// Replaces the object of `element` if `operation_ts` is newer than its last update, so
+    /// concurrent updates agree on the last writer. Deleted elements stay deleted, but get the
+    /// new object back if they are restored.
+    pub fn update(
+        &mut self,
+        position: S4Vector,
+        object: T,
+        operation_ts: S4Vector,
+    ) -> Result<(), Error> {
+        let element = self
+            .resolve(position)
+            .ok_or(Error::UnknownPosition(position))?;
+        let el = &mut self.nodes.get_mut(&element).unwrap().state;
+        if el.object.is_some() && operation_ts > el.value_clock {
+            el.object = Some(object);
+            el.value_clock = operation_ts;
+        }
+        Ok(())
+    }
+
+    /// Deletes all `elements` with the same timestamp. Either all of them are deleted or none.
+    pub fn delete_range(
+        &mut self,
+        elements: &[S4Vector],
+        operation_ts: S4Vector,
+    ) -> Result<(), Error> {
+        if let Some(missing) = elements.iter().find(|pos| !self.nodes.contains_key(pos)) {
+            return Err(Error::UnknownPosition(*missing));
+        }
+        for element in elements {
+            self.delete(*element, operation_ts)?;
+        }
+        Ok(())
+    }
+
+    /// Timestamp of the delete of the element at `position`, `None` if it isn't deleted.
+    pub fn deleted_at(&self, position: S4Vector) -> Option<S4Vector> {
+        let state = &self.nodes.get(&self.resolve(position)?)?.state;
+        state.deleted.then_some(state.update_clock)
+    }
+
+    /// Restores all `elements` with the same timestamp. Either all of them are restored or none.
+    pub fn restore_range(
+        &mut self,
+        elements: &[S4Vector],
+        operation_ts: S4Vector,
+    ) -> Result<(), Error> {
+        if let Some(missing) = elements.iter().find(|pos| !self.nodes.contains_key(pos)) {
+            return Err(Error::UnknownPosition(*missing));
+        }
+        for element in elements {
+            self.restore(*element, operation_ts)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the elements, deletions, updates and moves of `other`. `seen` is the clock of this
+    /// replica, elements it covers but that are missing here were purged and aren't added again.
+    pub fn merge(&mut self, other: &RGA<T>, seen: &[u32]) -> Result<(), Error> {
+        let is_seen = |pos: S4Vector| {
+            seen.get(pos.sid as usize)
+                .is_some_and(|value| *value >= pos.seq)
+        };
+        // the state of every element both replicas know before any slot of `other` is added
+        let mut elements: HashMap<S4Vector, Option<State<T>>> = HashMap::new();
+        for position in other.nodes.keys().filter(|pos| **pos != S4Vector::root()) {
+            let element = other.element_of(*position);
+            elements.entry(element).or_insert_with(|| {
+                let node = self.nodes.get(&self.current_slot(element))?;
+                Some(node.state.clone())
+            });
+        }
+
+        // origins always come before the nodes inserted after them, so walking the sequence
+        // of `other` integrates every node after its origin
+        let mut link = other.nodes[&S4Vector::root()].link;
+        while let Some(position) = link {
+            let node = &other.nodes[&position];
+            link = node.link;
+            if self.nodes.contains_key(&position) || is_seen(position) {
+                continue;
+            }
+            self.integrate(node.origin, position, node.state.clone())?;
+            if let Some(element) = node.element {
+                self.nodes.get_mut(&position).unwrap().element = Some(element);
+                self.moves.entry(element).or_default().push(position);
+            }
+        }
+
+        for (element, state) in elements {
+            let theirs = other
+                .nodes
+                .get(&other.current_slot(element))
+                .map(|node| node.state.clone());
+            let state = match (state, theirs) {
+                (Some(ours), Some(theirs)) => combine(ours, theirs),
+                (Some(state), None) | (None, Some(state)) => state,
+                (None, None) => continue,
+            };
+            self.settle(element, state);
+        }
+        Ok(())
+    }
+
+    /// Removes tombstones whose deletion every site has seen. `stable` is the minimum of the
+    /// clocks of all sites, a position is stable if its operation is covered by it.
+    ///
+    /// A tombstone is only removed if the insertion of its successor is stable as well. Every
+    /// later insertion then has a larger timestamp than the successor and stops in front of it
+    /// just like it would have stopped in front of the tombstone.
+    pub fn purge_tombstones(&mut self, stable: &[u32]) -> usize {
+        self.purge_tombstones_except(stable, |_| false)
+    }
+
+    /// Like [`RGA::purge_tombstones`], but keeps the tombstones `keep` returns true for.
+    pub fn purge_tombstones_except(
+        &mut self,
+        stable: &[u32],
+        keep: impl Fn(S4Vector) -> bool,
+    ) -> usize {
+        let is_stable = |pos: S4Vector| {
+            stable
+                .get(pos.sid as usize)
+                .is_some_and(|value| *value >= pos.seq)
+        };
+        let mut purged = 0;
+        let mut previous = S4Vector::root();
+        while let Some(current) = self.nodes[&previous].link {
+            let node = &self.nodes[&current];
+            // the slot an element was inserted at identifies it as long as it is moved around
+            let removable = node.state.visible().is_none()
+                && !keep(current)
+                && !self.moves.contains_key(&current)
+                && is_stable(node.state.update_clock)
+                && node.link.is_none_or(is_stable);
+            if !removable {
+                previous = current;
+                continue;
+            }
+            let link = node.link;
+            if let Some(element) = node.element {
+                let slots = self.moves.get_mut(&element).unwrap();
+                slots.retain(|slot| *slot != current);
+                if slots.is_empty() {
+                    self.moves.remove(&element);
+                }
+            }
+            self.nodes.remove(&current);
+            self.nodes.get_mut(&previous).unwrap().link = link;
+            self.index.remove(current);
+            purged += 1;
+        }
+        purged
+    }
+
+    /// Number of visible elements.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Position of the `index`-th visible element, index 0 is the root.
+    pub fn position_at(&self, index: usize) -> Option<S4Vector> {
+        if index == 0 {
+            return Some(S4Vector::root());
+        }
+        self.index.select(index)
+    }
+
+    /// Number of visible elements up to and including `position`.
+    pub fn index_of(&self, position: S4Vector) -> Option<usize> {
+        self.index.rank(position)
     }
 
     pub fn iter(&self) -> SnapshotIter<'_, T> {
@@ -59,6 +423,100 @@ impl<T: Clone + Default> RGA<T> {
             link: S4Vector::root(),
         }
     }
+
+    /// All nodes including the root and tombstones in sequence order.
+    pub fn snapshot(&self) -> Vec<NodeSnapshot<T>> {
+        let root = &self.nodes[&S4Vector::root()];
+        let mut nodes = vec![NodeSnapshot {
+            position: S4Vector::root().to_array(),
+            object: None,
+            update_clock: root.state.update_clock.to_array(),
+            deleted: false,
+            value_clock: None,
+            origin: root.origin.to_array(),
+            link: root.link.map(|link| link.to_array()),
+            element: None,
+        }];
+        let mut link = root.link;
+        while let Some(position) = link {
+            let node = &self.nodes[&position];
+            nodes.push(NodeSnapshot {
+                position: position.to_array(),
+                object: node.state.object.clone(),
+                update_clock: node.state.update_clock.to_array(),
+                deleted: node.state.deleted,
+                value_clock: Some(node.state.value_clock.to_array()),
+                origin: node.origin.to_array(),
+                link: node.link.map(|link| link.to_array()),
+                element: node.element.map(|element| element.to_array()),
+            });
+            link = node.link;
+        }
+        nodes
+    }
+
+    pub fn from_snapshot(snapshot: Vec<NodeSnapshot<T>>) -> Result<RGA<T>, Error> {
+        let mut nodes = HashMap::new();
+        let mut moves: HashMap<S4Vector, Vec<S4Vector>> = HashMap::new();
+        for node in snapshot {
+            let position = S4Vector::from(node.position);
+            let node = Node {
+                state: State {
+                    object: node.object,
+                    deleted: node.deleted,
+                    update_clock: node.update_clock.into(),
+                    value_clock: node.value_clock.unwrap_or(node.update_clock).into(),
+                },
+                origin: node.origin.into(),
+                link: node.link.map(S4Vector::from),
+                element: node.element.map(S4Vector::from),
+            };
+            if let Some(element) = node.element {
+                moves.entry(element).or_default().push(position);
+            }
+            if nodes.insert(position, node).is_some() {
+                return Err(Error::DuplicatePosition(position));
+            }
+        }
+        let root = nodes
+            .get(&S4Vector::root())
+            .ok_or(Error::MalformedSnapshot)?;
+        if root.state.object.is_some() {
+            return Err(Error::MalformedSnapshot);
+        }
+
+        let mut index = OrderIndex::new(S4Vector::root());
+        let mut previous = S4Vector::root();
+        let mut linked = 1;
+        while let Some(position) = nodes[&previous].link {
+            let node = nodes
+                .get(&position)
+                .ok_or(Error::UnknownPosition(position))?;
+            // a link back into the sequence would make it cyclic
+            if index.contains(position) {
+                return Err(Error::MalformedSnapshot);
+            }
+            index.insert_after(previous, position, node.state.visible().is_some());
+            previous = position;
+            linked += 1;
+        }
+        if linked != nodes.len() {
+            return Err(Error::MalformedSnapshot);
+        }
+        Ok(RGA {
+            nodes,
+            index,
+            moves,
+        })
+    }
+
+    /// Iterates over the elements behind `position`.
+    pub fn iter_after(&self, position: S4Vector) -> SnapshotIter<'_, T> {
+        SnapshotIter {
+            nodes: &self.nodes,
+            link: position,
+        }
+    }
 }
 
 pub struct SnapshotIter<'a, T> {
@@ -70,10 +528,10 @@ impl<T: Clone> Iterator for SnapshotIter<'_, T> {
     type Item = (S4Vector, Option<T>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let next_link = self.nodes[&self.link].link?;
+        let next_link = self.nodes.get(&self.link)?.link?;
         self.link = next_link;
-        let n = &self.nodes[&self.link];
-        Some((next_link, n.object.clone()))
+        let n = self.nodes.get(&self.link)?;
+        Some((next_link, n.state.visible().cloned()))
     }
 }
 
@@ -86,23 +544,23 @@ fn test_simple_insertion() {
 
     let mut clk = VectorClock::new(0);
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'h');
+    rga.insert(pos, clk.to_s4vector(), 'h').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'e');
+    rga.insert(pos, clk.to_s4vector(), 'e').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'l');
+    rga.insert(pos, clk.to_s4vector(), 'l').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'l');
+    rga.insert(pos, clk.to_s4vector(), 'l').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'o');
+    rga.insert(pos, clk.to_s4vector(), 'o').unwrap();
 
     assert_eq!(
         rga.iter().map(|(_, c)| c.unwrap()).collect::<String>(),
@@ -110,6 +568,29 @@ fn test_simple_insertion() {
     );
 }
 
+#[test]
+fn test_insert_run() {
+    let mut rga = RGA::new();
+    let first = S4Vector::from([0, 0, 1, 1]);
+    rga.insert_run(S4Vector::root(), first, "helo".chars().collect())
+        .unwrap();
+    // a later insertion of another site lands in the middle of the run
+    rga.insert(first.run_position(2), [0, 1, 5, 1].into(), 'l')
+        .unwrap();
+    assert_eq!(
+        rga.iter().map(|(_, c)| c.unwrap()).collect::<String>(),
+        "hello"
+    );
+    assert_eq!(rga.position_at(4), Some(S4Vector::from([0, 1, 5, 1])));
+
+    // nothing of a run is inserted if one of its positions exists already
+    assert_eq!(
+        rga.insert_run(S4Vector::root(), [0, 1, 4, 0].into(), vec!['a', 'b']),
+        Err(Error::DuplicatePosition([0, 1, 5, 1].into()))
+    );
+    assert_eq!(rga.len(), 5);
+}
+
 #[test]
 fn test_delete() {
     use super::clocks::VectorClock;
@@ -119,23 +600,23 @@ fn test_delete() {
 
     let mut clk = VectorClock::new(0);
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'a');
+    rga.insert(pos, clk.to_s4vector(), 'a').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
     let to_delete = clk.to_s4vector();
-    rga.insert(pos, clk.to_s4vector(), 'b');
+    rga.insert(pos, clk.to_s4vector(), 'b').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'b');
+    rga.insert(pos, clk.to_s4vector(), 'b').unwrap();
     pos = clk.to_s4vector();
 
     clk.increase();
-    rga.delete(to_delete, clk.to_s4vector());
+    rga.delete(to_delete, clk.to_s4vector()).unwrap();
 
     clk.increase();
-    rga.insert(pos, clk.to_s4vector(), 'c');
+    rga.insert(pos, clk.to_s4vector(), 'c').unwrap();
 
     assert_eq!(
         rga.iter()
@@ -146,8 +627,302 @@ fn test_delete() {
     );
 }
 
+#[test]
+fn test_index_lookups() {
+    let mut rga = RGA::new();
+    let positions: Vec<S4Vector> = (1..=5).map(|seq| [0, 0, seq, seq].into()).collect();
+    let mut pos = S4Vector::root();
+    for (p, c) in positions.iter().zip("hello".chars()) {
+        rga.insert(pos, *p, c).unwrap();
+        pos = *p;
+    }
+    rga.delete(positions[1], [0, 0, 6, 6].into()).unwrap();
+
+    assert_eq!(rga.len(), 4);
+    assert_eq!(rga.position_at(0), Some(S4Vector::root()));
+    assert_eq!(rga.position_at(2), Some(positions[2]));
+    assert_eq!(rga.position_at(5), None);
+    assert_eq!(rga.index_of(positions[1]), Some(1));
+    assert_eq!(rga.index_of(positions[4]), Some(4));
+    assert_eq!(
+        rga.iter_after(positions[2])
+            .filter_map(|(_, c)| c)
+            .collect::<String>(),
+        "lo"
+    );
+}
+
+#[test]
+fn test_purge_tombstones() {
+    let mut rga = RGA::new();
+    let positions: Vec<S4Vector> = (1..=4).map(|seq| [0, 0, seq, seq].into()).collect();
+    let mut pos = S4Vector::root();
+    for (p, c) in positions.iter().zip("abcd".chars()) {
+        rga.insert(pos, *p, c).unwrap();
+        pos = *p;
+    }
+    rga.delete(positions[1], [0, 0, 5, 5].into()).unwrap();
+    rga.delete(positions[2], [0, 1, 6, 1].into()).unwrap();
+
+    // the deletion of 'c' by site 1 is not stable yet
+    assert_eq!(rga.purge_tombstones(&[5, 0]), 1);
+    assert!(!rga.contains(&positions[1]));
+    assert!(rga.contains(&positions[2]));
+    assert_eq!(rga.purge_tombstones(&[5, 1]), 1);
+    assert!(!rga.contains(&positions[2]));
+
+    rga.insert(positions[0], [0, 1, 7, 2].into(), 'x').unwrap();
+    assert_eq!(rga.iter().filter_map(|(_, c)| c).collect::<String>(), "axd");
+    assert_eq!(rga.index_of(positions[3]), Some(3));
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let mut rga = RGA::new();
+    rga.insert(S4Vector::root(), [0, 0, 1, 1].into(), 'a')
+        .unwrap();
+    rga.insert([0, 0, 1, 1].into(), [0, 0, 2, 2].into(), 'b')
+        .unwrap();
+    rga.insert([0, 0, 2, 2].into(), [0, 0, 3, 3].into(), 'c')
+        .unwrap();
+    rga.delete([0, 0, 2, 2].into(), [0, 0, 4, 4].into())
+        .unwrap();
+
+    let snapshot = rga.snapshot();
+    assert_eq!(snapshot.len(), 4);
+    let restored = RGA::from_snapshot(snapshot.clone()).unwrap();
+    assert_eq!(restored.snapshot(), snapshot);
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored.index_of([0, 0, 3, 3].into()), Some(2));
+
+    let mut cyclic = snapshot.clone();
+    cyclic[3].link = Some([0, 0, 1, 1]);
+    assert!(matches!(
+        RGA::from_snapshot(cyclic),
+        Err(Error::MalformedSnapshot)
+    ));
+    let mut dangling = snapshot;
+    dangling[3].link = Some([0, 0, 9, 9]);
+    assert!(matches!(
+        RGA::from_snapshot(dangling),
+        Err(Error::UnknownPosition(_))
+    ));
+}
+
+#[test]
+fn test_merge() {
+    let mut rga1 = RGA::new();
+    let mut rga2 = RGA::new();
+    for rga in [&mut rga1, &mut rga2] {
+        rga.insert(S4Vector::root(), [0, 0, 1, 1].into(), 'a')
+            .unwrap();
+        rga.insert([0, 0, 1, 1].into(), [0, 0, 2, 2].into(), 'b')
+            .unwrap();
+    }
+    // concurrently both insert after 'a' and delete 'b'
+    rga1.insert([0, 0, 1, 1].into(), [0, 1, 3, 1].into(), 'x')
+        .unwrap();
+    rga1.insert([0, 1, 3, 1].into(), [0, 1, 4, 2].into(), 'y')
+        .unwrap();
+    rga1.delete([0, 0, 2, 2].into(), [0, 1, 5, 3].into())
+        .unwrap();
+    rga2.insert([0, 0, 1, 1].into(), [0, 2, 3, 1].into(), 'z')
+        .unwrap();
+    rga2.delete([0, 0, 2, 2].into(), [0, 2, 4, 2].into())
+        .unwrap();
+
+    let mut merged1 = RGA::from_snapshot(rga1.snapshot()).unwrap();
+    merged1.merge(&rga2, &[2, 3]).unwrap();
+    let mut merged2 = RGA::from_snapshot(rga2.snapshot()).unwrap();
+    merged2.merge(&rga1, &[2, 0, 2]).unwrap();
+
+    assert_eq!(merged1.snapshot(), merged2.snapshot());
+    assert_eq!(
+        merged1.iter().filter_map(|(_, c)| c).collect::<String>(),
+        "azxy"
+    );
+    assert_eq!(merged1.len(), 4);
+}
+
+#[test]
+fn test_update() {
+    let a = S4Vector::from([0, 0, 1, 1]);
+    let mut rga1 = RGA::new();
+    rga1.insert(S4Vector::root(), a, 'a').unwrap();
+    rga1.insert(a, [0, 0, 2, 2].into(), 'b').unwrap();
+    let mut rga2 = RGA::from_snapshot(rga1.snapshot()).unwrap();
+
+    // concurrent updates of 'a' are applied in different orders, the larger timestamp wins
+    rga1.update(a, 'x', [0, 1, 3, 1].into()).unwrap();
+    rga1.update(a, 'y', [0, 2, 3, 1].into()).unwrap();
+    rga2.update(a, 'y', [0, 2, 3, 1].into()).unwrap();
+    rga2.update(a, 'x', [0, 1, 3, 1].into()).unwrap();
+    assert_eq!(rga1.snapshot(), rga2.snapshot());
+    assert_eq!(rga1.iter().filter_map(|(_, c)| c).collect::<String>(), "yb");
+
+    // an update never brings back a deleted element
+    rga1.delete([0, 0, 2, 2].into(), [0, 1, 4, 2].into())
+        .unwrap();
+    rga2.update([0, 0, 2, 2].into(), 'z', [0, 2, 4, 2].into())
+        .unwrap();
+    rga1.merge(&rga2, &[2, 2, 1]).unwrap();
+    rga2.merge(&rga1, &[2, 1, 2]).unwrap();
+    assert_eq!(rga1.snapshot(), rga2.snapshot());
+    assert_eq!(rga2.iter().filter_map(|(_, c)| c).collect::<String>(), "y");
+}
+
+#[test]
+fn test_restore() {
+    let a = S4Vector::from([0, 0, 1, 1]);
+    let mut rga1 = RGA::new();
+    rga1.insert_run(S4Vector::root(), a, "ab".chars().collect())
+        .unwrap();
+    let mut rga2 = RGA::from_snapshot(rga1.snapshot()).unwrap();
+    let mut rga3 = RGA::from_snapshot(rga1.snapshot()).unwrap();
+
+    // site 1 deletes and restores 'a' while site 2 deletes it, the newest of them wins
+    let delete1 = S4Vector::from([0, 1, 3, 1]);
+    let restore1 = S4Vector::from([0, 1, 4, 2]);
+    let delete2 = S4Vector::from([0, 2, 3, 1]);
+    rga1.delete(a, delete1).unwrap();
+    rga1.restore(a, restore1).unwrap();
+    rga1.delete(a, delete2).unwrap();
+    rga2.delete(a, delete2).unwrap();
+    rga2.delete(a, delete1).unwrap();
+    rga2.restore(a, restore1).unwrap();
+    assert_eq!(rga1.snapshot(), rga2.snapshot());
+    assert_eq!(rga1.get(a), Some(&'a'));
+
+    // the deleted element keeps concurrent updates and shows them once it's restored
+    rga3.delete(a, [0, 3, 3, 1].into()).unwrap();
+    rga3.update(a, 'x', [0, 3, 4, 2].into()).unwrap();
+    assert_eq!(rga3.len(), 1);
+    rga1.merge(&rga3, &[2, 2, 1]).unwrap();
+    rga3.merge(&rga2, &[2, 0, 0, 2]).unwrap();
+    assert_eq!(rga1.snapshot(), rga3.snapshot());
+    assert_eq!(rga1.iter().filter_map(|(_, c)| c).collect::<String>(), "xb");
+}
+
+#[test]
+fn test_move() {
+    let a = S4Vector::from([0, 0, 1, 1]);
+    let mut rga1 = RGA::new();
+    rga1.insert_run(S4Vector::root(), a, "abc".chars().collect())
+        .unwrap();
+    let mut rga2 = RGA::from_snapshot(rga1.snapshot()).unwrap();
+    let mut rga3 = RGA::from_snapshot(rga1.snapshot()).unwrap();
+    let behind_c = S4Vector::from([0, 1, 4, 1]);
+    let behind_b = S4Vector::from([0, 2, 4, 1]);
+
+    // both sites move 'a' concurrently, the move of site 2 wins in either order
+    rga1.move_to(a, [0, 0, 3, 3].into(), behind_c).unwrap();
+    rga1.move_to(a, [0, 0, 2, 2].into(), behind_b).unwrap();
+    rga2.move_to(a, [0, 0, 2, 2].into(), behind_b).unwrap();
+    rga2.move_to(a, [0, 0, 3, 3].into(), behind_c).unwrap();
+    assert_eq!(rga1.snapshot(), rga2.snapshot());
+    assert_eq!(
+        rga1.iter().filter_map(|(_, c)| c).collect::<String>(),
+        "bac"
+    );
+    assert_eq!(rga1.resolve(behind_c), Some(behind_b));
+
+    // the losing slot still refers to the element
+    rga2.update(behind_c, 'x', [0, 1, 5, 2].into()).unwrap();
+    rga3.move_to(a, [0, 0, 3, 3].into(), behind_c).unwrap();
+    rga3.delete(a, [0, 3, 5, 1].into()).unwrap();
+    rga2.merge(&rga3, &[3, 2, 1]).unwrap();
+    rga3.merge(&rga2, &[3, 1, 0, 1]).unwrap();
+    assert_eq!(rga2.snapshot(), rga3.snapshot());
+    assert_eq!(rga2.iter().filter_map(|(_, c)| c).collect::<String>(), "bc");
+    assert_eq!(rga2.len(), 2);
+
+    // the slot the element was inserted at outlives the collection of the losing slot
+    assert_eq!(rga1.purge_tombstones(&[3, 1, 1]), 1);
+    assert_eq!(rga1.resolve(a), Some(behind_b));
+    assert_eq!(rga1.resolve(behind_c), None);
+}
+
+#[test]
+fn test_unknown_positions() {
+    let mut rga = RGA::new();
+    let missing = S4Vector::from([0, 1, 1, 1]);
+    assert_eq!(
+        rga.insert(missing, [0, 1, 2, 2].into(), 'a'),
+        Err(Error::UnknownPosition(missing))
+    );
+    assert_eq!(
+        rga.delete(missing, [0, 1, 2, 2].into()),
+        Err(Error::UnknownPosition(missing))
+    );
+    rga.insert(S4Vector::root(), missing, 'a').unwrap();
+    assert_eq!(
+        rga.insert(S4Vector::root(), missing, 'b'),
+        Err(Error::DuplicatePosition(missing))
+    );
+}
+
 struct Node<T> {
+    state: State<T>,
+    // the position the node was inserted after
+    origin: S4Vector,
+    link: Option<S4Vector>,
+    // set for slots created by moving an element
+    element: Option<S4Vector>,
+}
+
+// What an element keeps when it is moved to another slot.
+#[derive(Clone)]
+struct State<T> {
+    // kept while the element is deleted
     object: Option<T>,
+    deleted: bool,
+    // orders the deletes and restores of the element
     update_clock: S4Vector,
-    link: Option<S4Vector>,
+    // orders the updates of the object
+    value_clock: S4Vector,
+}
+
+impl<T> State<T> {
+    fn new(object: T, clock: S4Vector) -> State<T> {
+        State {
+            object: Some(object),
+            deleted: false,
+            update_clock: clock,
+            value_clock: clock,
+        }
+    }
+
+    fn empty(clock: S4Vector) -> State<T> {
+        State {
+            object: None,
+            deleted: false,
+            update_clock: clock,
+            value_clock: clock,
+        }
+    }
+
+    fn visible(&self) -> Option<&T> {
+        self.object.as_ref().filter(|_| !self.deleted)
+    }
+}
+
+// Combines the states of an element on two replicas: the newer delete or restore decides if it
+// is deleted and the newer update decides its object, independent of each other.
+fn combine<T>(ours: State<T>, theirs: State<T>) -> State<T> {
+    let (deleted, update_clock) = if theirs.update_clock > ours.update_clock {
+        (theirs.deleted, theirs.update_clock)
+    } else {
+        (ours.deleted, ours.update_clock)
+    };
+    let (object, value_clock) = if theirs.value_clock > ours.value_clock {
+        (theirs.object, theirs.value_clock)
+    } else {
+        (ours.object, ours.value_clock)
+    };
+    State {
+        object,
+        deleted,
+        update_clock,
+        value_clock,
+    }
 }
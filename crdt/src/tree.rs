@@ -0,0 +1,431 @@
+//! A tree whose nodes are moved concurrently. Every node is identified by the timestamp of
+//! the operation that created it and ordered among its siblings by an [`RGA`] of slots, a move
+//! puts the node into a new slot of its new parent.
+//!
+//! Moves are applied in timestamp order. A move that arrives late undoes the moves with larger
+//! timestamps, is applied and redoes them again. Moves that would make a node its own ancestor
+//! are skipped, so every replica skips the same moves and the tree never contains a cycle.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    causal::{deliver, CausalBuffer, CausalOperation, CausalReplica},
+    clocks::{S4Vector, VectorClock},
+    data_structure::ApplyStatus,
+    rga::RGA,
+    Error,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TreeOperationData<T> {
+    /// Creates a node at the position of the operation behind the slot `insert_after` of the
+    /// children of `parent`.
+    Create {
+        parent: [u32; 4],
+        insert_after: [u32; 4],
+        value: T,
+    },
+    Move {
+        node: [u32; 4],
+        parent: [u32; 4],
+        insert_after: [u32; 4],
+    },
+    /// Removes the node and everything below it from the tree.
+    Delete([u32; 4]),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeOperation<T> {
+    pub sent_by: usize,
+    pub op_clock: Vec<u32>,
+    pub data: TreeOperationData<T>,
+}
+
+impl<T> CausalOperation for TreeOperation<T> {
+    fn sent_by(&self) -> usize {
+        self.sent_by
+    }
+
+    fn op_clock(&self) -> &[u32] {
+        &self.op_clock
+    }
+
+    fn first_clock(&self) -> Vec<u32> {
+        self.op_clock.clone()
+    }
+}
+
+struct TreeNode<T> {
+    value: T,
+    parent: S4Vector,
+    slot: S4Vector,
+    deleted: bool,
+}
+
+struct LogEntry {
+    timestamp: S4Vector,
+    node: S4Vector,
+    parent: S4Vector,
+    // parent and slot before the move, `None` if the move was skipped
+    previous: Option<(S4Vector, S4Vector)>,
+}
+
+pub struct Tree<T> {
+    clock: VectorClock,
+    nodes: HashMap<S4Vector, TreeNode<T>>,
+    // the slots of the children of every node, a slot is empty once its node moved on
+    children: HashMap<S4Vector, RGA<S4Vector>>,
+    // moves in timestamp order
+    log: Vec<LogEntry>,
+    pending: CausalBuffer<TreeOperation<T>>,
+}
+
+impl<T: Clone> Tree<T> {
+    pub fn new(id: usize) -> Tree<T> {
+        Tree {
+            clock: VectorClock::new(id),
+            nodes: HashMap::new(),
+            children: HashMap::from([(S4Vector::root(), RGA::new())]),
+            log: Vec::new(),
+            pending: CausalBuffer::new(),
+        }
+    }
+
+    /// The root has no value and can't be moved or deleted.
+    pub fn root() -> S4Vector {
+        S4Vector::root()
+    }
+
+    pub fn get(&self, node: S4Vector) -> Option<&T> {
+        Some(&self.nodes.get(&node)?.value)
+    }
+
+    pub fn parent(&self, node: S4Vector) -> Option<S4Vector> {
+        Some(self.nodes.get(&node)?.parent)
+    }
+
+    /// The children of `node` that aren't deleted, in order.
+    pub fn children(&self, node: S4Vector) -> Vec<S4Vector> {
+        let Some(slots) = self.children.get(&node) else {
+            return Vec::new();
+        };
+        slots
+            .iter()
+            .filter_map(|(slot, child)| {
+                let child = child?;
+                let state = &self.nodes[&child];
+                (state.slot == slot && !state.deleted).then_some(child)
+            })
+            .collect()
+    }
+
+    /// Whether `node` is part of the tree, i.e. neither it nor one of its ancestors is deleted.
+    pub fn contains(&self, node: S4Vector) -> bool {
+        let mut current = node;
+        while current != Tree::<T>::root() {
+            match self.nodes.get(&current) {
+                Some(state) if !state.deleted => current = state.parent,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Creates a node with `value` as the `index`-th child of `parent`.
+    pub fn local_create(
+        &mut self,
+        parent: S4Vector,
+        index: usize,
+        value: T,
+    ) -> Result<TreeOperation<T>, Error> {
+        let insert_after = self.slot_before(parent, index, None)?;
+        self.local_operation(TreeOperationData::Create {
+            parent: parent.to_array(),
+            insert_after: insert_after.to_array(),
+            value,
+        })
+    }
+
+    /// Moves `node` with everything below it to become the `index`-th child of `parent`.
+    pub fn local_move(
+        &mut self,
+        node: S4Vector,
+        parent: S4Vector,
+        index: usize,
+    ) -> Result<TreeOperation<T>, Error> {
+        if !self.nodes.contains_key(&node) {
+            return Err(Error::UnknownPosition(node));
+        }
+        if self.is_ancestor(node, parent) {
+            return Err(Error::CyclicMove(node));
+        }
+        let insert_after = self.slot_before(parent, index, Some(node))?;
+        self.local_operation(TreeOperationData::Move {
+            node: node.to_array(),
+            parent: parent.to_array(),
+            insert_after: insert_after.to_array(),
+        })
+    }
+
+    pub fn local_delete(&mut self, node: S4Vector) -> Result<TreeOperation<T>, Error> {
+        if !self.nodes.contains_key(&node) {
+            return Err(Error::UnknownPosition(node));
+        }
+        self.local_operation(TreeOperationData::Delete(node.to_array()))
+    }
+
+    pub fn get_clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    pub fn pending_operations(&self) -> &[TreeOperation<T>] {
+        self.pending.pending()
+    }
+
+    /// Buffered operations that failed to integrate once they were ready, with their error.
+    pub fn failed_operations(&self) -> &[(TreeOperation<T>, Error)] {
+        self.pending.failed()
+    }
+
+    /// Delivers `operation` like [`SynchronizedSequence::apply_operation`].
+    ///
+    /// [`SynchronizedSequence::apply_operation`]: crate::data_structure::SynchronizedSequence::apply_operation
+    pub fn apply_operation(&mut self, operation: &TreeOperation<T>) -> Result<ApplyStatus, Error> {
+        deliver(self, operation)
+    }
+
+    // the slot a child inserted at `index` of `parent` follows, ignoring `moving`
+    fn slot_before(
+        &self,
+        parent: S4Vector,
+        index: usize,
+        moving: Option<S4Vector>,
+    ) -> Result<S4Vector, Error> {
+        if !self.children.contains_key(&parent) {
+            return Err(Error::UnknownPosition(parent));
+        }
+        let siblings: Vec<S4Vector> = self
+            .children(parent)
+            .into_iter()
+            .filter(|child| Some(*child) != moving)
+            .collect();
+        match index {
+            0 => Ok(S4Vector::root()),
+            _ => siblings
+                .get(index - 1)
+                .map(|sibling| self.nodes[sibling].slot)
+                .ok_or(Error::IndexOutOfBounds {
+                    index,
+                    len: siblings.len(),
+                }),
+        }
+    }
+
+    fn local_operation(&mut self, data: TreeOperationData<T>) -> Result<TreeOperation<T>, Error> {
+        self.clock.increase();
+        let operation = TreeOperation {
+            sent_by: self.clock.id(),
+            op_clock: self.clock.clock_values().to_vec(),
+            data,
+        };
+        self.integrate(&operation)?;
+        Ok(operation)
+    }
+
+    fn integrate_move(&mut self, timestamp: S4Vector, node: S4Vector, parent: S4Vector) {
+        let idx = self
+            .log
+            .partition_point(|entry| entry.timestamp < timestamp);
+        let later = self.log.split_off(idx);
+        for entry in later.iter().rev() {
+            if let Some((parent, slot)) = entry.previous {
+                let state = self.nodes.get_mut(&entry.node).unwrap();
+                state.parent = parent;
+                state.slot = slot;
+            }
+        }
+        let entry = self.do_move(timestamp, node, parent);
+        self.log.push(entry);
+        for entry in later {
+            let entry = self.do_move(entry.timestamp, entry.node, entry.parent);
+            self.log.push(entry);
+        }
+    }
+
+    fn do_move(&mut self, timestamp: S4Vector, node: S4Vector, parent: S4Vector) -> LogEntry {
+        let previous = if self.is_ancestor(node, parent) {
+            None
+        } else {
+            let state = self.nodes.get_mut(&node).unwrap();
+            let previous = (state.parent, state.slot);
+            state.parent = parent;
+            state.slot = timestamp;
+            Some(previous)
+        };
+        LogEntry {
+            timestamp,
+            node,
+            parent,
+            previous,
+        }
+    }
+
+    // whether `ancestor` is `node` or lies on the path from `node` to the root
+    fn is_ancestor(&self, ancestor: S4Vector, node: S4Vector) -> bool {
+        let mut current = node;
+        loop {
+            if current == ancestor {
+                return true;
+            }
+            match self.nodes.get(&current) {
+                Some(state) => current = state.parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+impl<T: Clone> CausalReplica for Tree<T> {
+    type Operation = TreeOperation<T>;
+
+    fn causal_parts(&mut self) -> (&mut VectorClock, &mut CausalBuffer<TreeOperation<T>>) {
+        (&mut self.clock, &mut self.pending)
+    }
+
+    fn integrate(&mut self, operation: &TreeOperation<T>) -> Result<(), Error> {
+        let timestamp =
+            VectorClock::from_parts(operation.sent_by, operation.op_clock.clone())?.to_s4vector();
+        match &operation.data {
+            TreeOperationData::Create {
+                parent,
+                insert_after,
+                value,
+            } => {
+                let parent = S4Vector::from(*parent);
+                self.children
+                    .get_mut(&parent)
+                    .ok_or(Error::UnknownPosition(parent))?
+                    .insert((*insert_after).into(), timestamp, timestamp)?;
+                self.children.insert(timestamp, RGA::new());
+                self.nodes.insert(
+                    timestamp,
+                    TreeNode {
+                        value: value.clone(),
+                        parent,
+                        slot: timestamp,
+                        deleted: false,
+                    },
+                );
+            }
+            TreeOperationData::Move {
+                node,
+                parent,
+                insert_after,
+            } => {
+                let (node, parent) = (S4Vector::from(*node), S4Vector::from(*parent));
+                if !self.nodes.contains_key(&node) {
+                    return Err(Error::UnknownPosition(node));
+                }
+                // the slot is added even if the move is skipped, inserting slots commutes
+                self.children
+                    .get_mut(&parent)
+                    .ok_or(Error::UnknownPosition(parent))?
+                    .insert((*insert_after).into(), timestamp, node)?;
+                self.integrate_move(timestamp, node, parent);
+            }
+            TreeOperationData::Delete(node) => {
+                let node = S4Vector::from(*node);
+                self.nodes
+                    .get_mut(&node)
+                    .ok_or(Error::UnknownPosition(node))?
+                    .deleted = true;
+            }
+        }
+        self.clock.merge_remote(&operation.op_clock);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outline() {
+        let mut tree0 = Tree::new(0);
+        let mut tree1 = Tree::<String>::new(1);
+        let root = Tree::<String>::root();
+        let mut ops = vec![tree0.local_create(root, 0, "intro".to_owned()).unwrap()];
+        let intro = tree0.children(root)[0];
+        ops.push(tree0.local_create(root, 1, "details".to_owned()).unwrap());
+        ops.push(
+            tree0
+                .local_create(intro, 0, "motivation".to_owned())
+                .unwrap(),
+        );
+        let motivation = tree0.children(intro)[0];
+        ops.push(tree0.local_create(root, 0, "summary".to_owned()).unwrap());
+        ops.push(tree0.local_move(motivation, root, 1).unwrap());
+        assert_eq!(
+            tree0.local_move(intro, intro, 0).unwrap_err(),
+            Error::CyclicMove(intro)
+        );
+
+        for op in ops.iter().rev() {
+            let json = serde_json::to_string(op).unwrap();
+            tree1
+                .apply_operation(&serde_json::from_str(&json).unwrap())
+                .unwrap();
+        }
+        assert!(tree1.pending_operations().is_empty());
+        let titles: Vec<&str> = tree1
+            .children(root)
+            .into_iter()
+            .map(|node| tree1.get(node).unwrap().as_str())
+            .collect();
+        assert_eq!(titles, ["summary", "motivation", "intro", "details"]);
+        assert!(tree1.children(intro).is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_moves() {
+        let mut tree0 = Tree::new(0);
+        let mut tree1 = Tree::new(1);
+        let root = Tree::<char>::root();
+        let base = [
+            tree0.local_create(root, 0, 'a').unwrap(),
+            tree0.local_create(root, 1, 'b').unwrap(),
+            tree0.local_create(root, 2, 'c').unwrap(),
+        ];
+        for op in &base {
+            tree1.apply_operation(op).unwrap();
+        }
+        let [a, b, c] = tree0.children(root)[..] else {
+            panic!("three children were created");
+        };
+
+        // moving a below b and b below a would create a cycle, the later move is skipped
+        let move0 = tree0.local_move(a, b, 0).unwrap();
+        let move1 = tree1.local_move(b, a, 0).unwrap();
+        // c is moved to different parents, the later move wins
+        let move_c0 = tree0.local_move(c, b, 0).unwrap();
+        let move_c1 = tree1.local_move(c, a, 0).unwrap();
+        let delete = tree1.local_delete(b).unwrap();
+        for op in [&move1, &move_c1, &delete] {
+            tree0.apply_operation(op).unwrap();
+        }
+        for op in [&move0, &move_c0] {
+            tree1.apply_operation(op).unwrap();
+        }
+
+        for tree in [&tree0, &tree1] {
+            assert_eq!(tree.parent(a), Some(b));
+            assert_eq!(tree.parent(b), Some(root));
+            assert_eq!(tree.parent(c), Some(a));
+            assert!(tree.children(root).is_empty());
+            assert!(!tree.contains(c));
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Undo and redo of the local changes to a [`SynchronizedSequence`]. Undoing only touches the
+//! values the local site inserted or deleted, concurrent changes of other sites stay.
+
+use crate::{
+    clocks::S4Vector,
+    data_structure::{Operation, OperationData, SynchronizedSequence},
+    Error,
+};
+
+#[derive(Debug, Clone)]
+enum Change {
+    Inserted(Vec<S4Vector>),
+    Deleted(Vec<S4Vector>),
+}
+
+/// Changes undone and redone together.
+#[derive(Debug, Clone)]
+struct UndoStep {
+    changes: Vec<Change>,
+}
+
+pub struct UndoManager {
+    undo_stack: Vec<UndoStep>,
+    redo_stack: Vec<UndoStep>,
+    // changes recorded within this time of the previous one join its step
+    group_interval: u64,
+    last_recorded: Option<u64>,
+}
+
+impl UndoManager {
+    /// Groups changes recorded less than `group_interval` apart into one undo step. Times are
+    /// supplied by the caller in any unit, e.g. milliseconds.
+    pub fn new(group_interval: u64) -> UndoManager {
+        UndoManager {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            group_interval,
+            last_recorded: None,
+        }
+    }
+
+    /// Records a local insert, delete or restore at time `now`. Other operations can't be
+    /// undone and are ignored.
+    pub fn record<T>(&mut self, operation: &Operation<T>, now: u64) {
+        let change = match &operation.data {
+            OperationData::Insert(_) | OperationData::InsertRun(_) => {
+                Change::Inserted(inserted_positions(operation))
+            }
+            OperationData::Restore(positions) => Change::Inserted(to_positions(positions)),
+            OperationData::Delete(position) => Change::Deleted(vec![(*position).into()]),
+            OperationData::DeleteRange(positions) => Change::Deleted(to_positions(positions)),
+            _ => return,
+        };
+        self.redo_stack.clear();
+        let joins = self
+            .last_recorded
+            .is_some_and(|last| now.saturating_sub(last) < self.group_interval);
+        match (self.undo_stack.last_mut(), change) {
+            (Some(step), change) if joins => match (step.changes.last_mut(), change) {
+                // e.g. typing or deleting one character after another
+                (Some(Change::Inserted(positions)), Change::Inserted(inserted)) => {
+                    positions.extend(inserted)
+                }
+                (Some(Change::Deleted(positions)), Change::Deleted(deleted)) => {
+                    positions.extend(deleted)
+                }
+                (_, change) => step.changes.push(change),
+            },
+            (_, change) => self.undo_stack.push(UndoStep {
+                changes: vec![change],
+            }),
+        }
+        self.last_recorded = Some(now);
+    }
+
+    /// Starts a new undo step with the next recorded change, e.g. when the cursor moved.
+    pub fn set_boundary(&mut self) {
+        self.last_recorded = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Reverts the latest undo step and returns the operations to send to the other sites.
+    /// A step that deleted values every site has seen deleted can't be reverted anymore, it is
+    /// dropped and the error is returned.
+    pub fn undo<T: Clone>(
+        &mut self,
+        sequence: &mut SynchronizedSequence<T>,
+    ) -> Result<Vec<Operation<T>>, Error> {
+        let Some(step) = self.undo_stack.pop() else {
+            return Ok(Vec::new());
+        };
+        let (operations, inverse) = revert(sequence, step)?;
+        self.redo_stack.push(inverse);
+        self.set_boundary();
+        Ok(operations)
+    }
+
+    /// Reapplies the latest undone step and returns the operations to send to the other sites.
+    /// Like [`UndoManager::undo`] it drops steps that can't be reapplied anymore.
+    pub fn redo<T: Clone>(
+        &mut self,
+        sequence: &mut SynchronizedSequence<T>,
+    ) -> Result<Vec<Operation<T>>, Error> {
+        let Some(step) = self.redo_stack.pop() else {
+            return Ok(Vec::new());
+        };
+        let (operations, inverse) = revert(sequence, step)?;
+        self.undo_stack.push(inverse);
+        self.set_boundary();
+        Ok(operations)
+    }
+}
+
+// applies the inverse of every change of `step`, latest first, and returns the step that
+// reverts this again
+fn revert<T: Clone>(
+    sequence: &mut SynchronizedSequence<T>,
+    step: UndoStep,
+) -> Result<(Vec<Operation<T>>, UndoStep), Error> {
+    // either the whole step is reverted or nothing
+    for change in &step.changes {
+        if let Change::Deleted(positions) = change {
+            sequence.check_restorable(positions)?;
+        }
+    }
+    let mut operations = Vec::new();
+    let mut changes = Vec::new();
+    for change in step.changes.into_iter().rev() {
+        match change {
+            Change::Inserted(positions) => {
+                // values deleted by others meanwhile stay deleted
+                let positions: Vec<S4Vector> = positions
+                    .into_iter()
+                    .filter(|pos| sequence.get(*pos).is_some())
+                    .collect();
+                operations.extend(sequence.local_delete_range(&positions)?);
+                changes.push(Change::Deleted(positions));
+            }
+            Change::Deleted(positions) => {
+                // the values come back where they were with their positions
+                operations.extend(sequence.local_restore(&positions)?);
+                changes.push(Change::Inserted(positions));
+            }
+        }
+    }
+    changes.reverse();
+    Ok((operations, UndoStep { changes }))
+}
+
+fn inserted_positions<T>(operation: &Operation<T>) -> Vec<S4Vector> {
+    match &operation.data {
+        OperationData::Insert(insert) => vec![insert.insert_position.into()],
+        OperationData::InsertRun(run) => {
+            let first = S4Vector::from(run.insert_position);
+            (0..run.values.len() as u32)
+                .map(|offset| first.run_position(offset))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn to_positions(positions: &[[u32; 4]]) -> Vec<S4Vector> {
+    positions.iter().map(|pos| S4Vector::from(*pos)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::SynchronizedText;
+
+    #[test]
+    fn test_undo_redo() {
+        let mut text = SynchronizedText::new(0);
+        let mut undo = UndoManager::new(500);
+        let mut cursor = S4Vector::root();
+        for (now, character) in "hi".chars().enumerate() {
+            let op = text.local_insert(cursor, character).unwrap();
+            cursor = text.get_clock().to_s4vector();
+            undo.record(&op, now as u64 * 100);
+        }
+        let op = text.insert_str_at(2, " there").unwrap();
+        undo.record(&op, 1000);
+        let op = text.delete_range(0, 1).unwrap().unwrap();
+        undo.record(&op, 1100);
+        assert_eq!(text.get_text(), "i there");
+
+        // the insert and delete at 1000 and 1100 are one step
+        undo.undo(&mut text).unwrap();
+        assert_eq!(text.get_text(), "hi");
+        undo.undo(&mut text).unwrap();
+        assert_eq!(text.get_text(), "");
+        assert!(!undo.can_undo());
+        undo.redo(&mut text).unwrap();
+        undo.redo(&mut text).unwrap();
+        assert_eq!(text.get_text(), "i there");
+        undo.undo(&mut text).unwrap();
+        assert_eq!(text.get_text(), "hi");
+    }
+
+    #[test]
+    fn test_only_local_changes() {
+        let mut text0 = SynchronizedText::new(0);
+        let mut text1 = SynchronizedText::new(1);
+        let mut undo = UndoManager::new(0);
+        let op = text0.insert_str_at(0, "ac").unwrap();
+        undo.record(&op, 0);
+        text1.apply_operation(&op).unwrap();
+        let remote = text1.insert_str_at(1, "b").unwrap();
+        text0.apply_operation(&remote).unwrap();
+
+        // undoing the insert of site 0 keeps the text site 1 typed in between
+        for op in undo.undo(&mut text0).unwrap() {
+            text1.apply_operation(&op).unwrap();
+        }
+        assert_eq!(text0.get_text(), "b");
+        assert_eq!(text1.get_text(), "b");
+        for op in undo.redo(&mut text0).unwrap() {
+            text1.apply_operation(&op).unwrap();
+        }
+        // the values are restored at their old positions
+        assert_eq!(text0.get_text(), "abc");
+        assert_eq!(text1.get_text(), "abc");
+    }
+
+    #[test]
+    fn test_stable_deletion() {
+        let mut text0 = SynchronizedText::new(0);
+        let mut text1 = SynchronizedText::new(1);
+        let mut undo = UndoManager::new(0);
+        let insert = text0.insert_str_at(0, "ab").unwrap();
+        let a = text0.position_at(1).unwrap();
+        let delete = text0.delete_range(0, 2).unwrap().unwrap();
+        undo.record(&insert, 0);
+        undo.record(&delete, 100);
+        text1.apply_operation(&insert).unwrap();
+        text1.apply_operation(&delete).unwrap();
+
+        // both sites have seen the deletion and site 1 collects the values
+        text0.add_peer(1);
+        text0.acknowledge(1, text1.get_clock().clock_values());
+        text1.add_peer(0);
+        assert_eq!(text1.collect_garbage(), 2);
+
+        // restoring them at site 0 only would make the sites diverge
+        assert_eq!(
+            text0.local_restore(&[a]).unwrap_err(),
+            Error::StableDeletion(a)
+        );
+        assert_eq!(undo.undo(&mut text0).unwrap_err(), Error::StableDeletion(a));
+        assert_eq!(text0.get_text(), "");
+        // the step is dropped, the one before it can still be undone
+        assert!(undo.can_undo());
+        undo.undo(&mut text0).unwrap();
+    }
+}
@@ -1,66 +1,70 @@
 extern crate rand;
 
+mod or_set;
+
 use rand::distributions::Standard;
 use rand::seq::IteratorRandom;
-use rand::seq::SliceRandom;
 use rand::{distributions::Alphanumeric, prelude::*};
 
 use crdt::{
     clocks::S4Vector,
-    data_structure::{Operation, SynchronizedText},
+    data_structure::{Operation, OperationData, SynchronizedText},
 };
 
 struct FuzzSuite {
     data_structures: Vec<SynchronizedText>,
-    pushed_operations: Vec<Vec<Operation>>,
-    operation_positions: Vec<Vec<usize>>,
+    inboxes: Vec<Vec<Operation>>,
     executed_operations: Vec<Vec<Operation>>,
     rng: ThreadRng,
     insert_probability: f32,
     delete_probability: f32,
+    update_probability: f32,
+    redelivery_probability: f32,
+    garbage_collection_probability: f32,
+    merge_probability: f32,
 }
 
 impl FuzzSuite {
     fn new(num_executors: usize, insert_probability: f32, delete_probability: f32) -> FuzzSuite {
         FuzzSuite {
             data_structures: (0..num_executors)
-                .map(|id| SynchronizedText::new(id))
+                .map(|id| {
+                    let mut text = SynchronizedText::new(id);
+                    (0..num_executors).for_each(|peer| text.add_peer(peer));
+                    text
+                })
                 .collect(),
-            pushed_operations: vec![vec![]; num_executors],
+            inboxes: vec![vec![]; num_executors],
             executed_operations: vec![vec![]; num_executors],
-            operation_positions: vec![vec![0; num_executors]; num_executors],
             rng: thread_rng(),
             insert_probability,
             delete_probability,
+            update_probability: 0.1,
+            redelivery_probability: 0.1,
+            garbage_collection_probability: 0.2,
+            merge_probability: 0.05,
         }
     }
     fn execute_all_pending(&mut self) {
-        let mut had_pending = true;
-        while had_pending {
-            had_pending = false;
-            let mut execute_succeeded = false;
-            for executor in 0..self.num_executors() {
-                for from_queue in 0..self.num_executors() {
-                    if executor == from_queue {
-                        continue;
-                    }
-                    if !self.has_pending_operations(executor, from_queue) {
-                        continue;
-                    }
-                    had_pending = true;
-
-                    while self.execute_op(executor, from_queue) {
-                        execute_succeeded = true;
-                    }
-                }
-            }
-            if had_pending && !execute_succeeded {
-                panic!("Couldn't execute any operations but had pending ones");
+        for executor in 0..self.num_executors() {
+            while self.execute_op(executor) {}
+            if !self.data_structures[executor]
+                .pending_operations()
+                .is_empty()
+            {
+                panic!("Delivered all operations but some are still pending");
             }
         }
     }
 
     fn perform_random_operation(&mut self, executor: usize) {
+        if self.rng.sample::<f32, _>(Standard) < self.garbage_collection_probability {
+            self.collect_garbage(executor);
+        }
+        if self.rng.sample::<f32, _>(Standard) < self.merge_probability {
+            let other = self.rng.gen_range(0..self.num_executors());
+            self.merge(executor, other);
+        }
         let random_number: f32 = self.rng.sample(Standard);
         let operation_position = self.data_structures[executor]
             .iter()
@@ -71,49 +75,140 @@ impl FuzzSuite {
 
         let op =
             if random_number < self.insert_probability || operation_position == S4Vector::root() {
-                let inserted = self.rng.sample(Alphanumeric) as char;
-                self.data_structures[executor].local_insert(operation_position, inserted)
+                let len = if self.rng.gen_ratio(1, 4) {
+                    self.rng.gen_range(2..6)
+                } else {
+                    1
+                };
+                let inserted: String = (0..len)
+                    .map(|_| self.rng.sample(Alphanumeric) as char)
+                    .collect();
+                self.data_structures[executor]
+                    .local_insert_str(operation_position, &inserted)
+                    .expect("Failed to insert")
+                    .expect("Inserted text is never empty")
             } else if random_number < self.insert_probability + self.delete_probability {
-                self.data_structures[executor].local_delete(operation_position)
+                let text = &mut self.data_structures[executor];
+                let index = text.index_of(operation_position).unwrap() - 1;
+                let len = if self.rng.gen_ratio(1, 4) {
+                    self.rng.gen_range(1..=(text.len() - index).min(5))
+                } else {
+                    1
+                };
+                text.delete_range(index, len)
+                    .expect("Failed to delete")
+                    .expect("Deleted range is never empty")
+            } else if random_number
+                < self.insert_probability + self.delete_probability + self.update_probability
+            {
+                let replacement = self.rng.sample(Alphanumeric) as char;
+                self.data_structures[executor]
+                    .local_update(operation_position, replacement)
+                    .expect("Failed to update")
             } else {
-                panic!("Update not implemented")
+                let text = &self.data_structures[executor];
+                let target = self.rng.gen_range(0..=text.len());
+                let insert_after = text.position_at(target).unwrap();
+                self.data_structures[executor]
+                    .local_move(operation_position, insert_after)
+                    .expect("Failed to move")
             };
-        self.pushed_operations[executor].push(op.clone());
-        self.executed_operations[executor].push(op);
+        self.broadcast(executor, op);
     }
 
-    fn num_executors(&self) -> usize {
-        self.data_structures.len()
+    fn broadcast(&mut self, executor: usize, op: Operation) {
+        for (receiver, inbox) in self.inboxes.iter_mut().enumerate() {
+            if receiver != executor {
+                inbox.push(op.clone());
+            }
+        }
+        self.executed_operations[executor].push(op);
     }
-    fn can_execute(&self, executor: usize, from_queue: usize) -> bool {
-        executor != from_queue
-            && self.has_pending_operations(executor, from_queue)
-            && self.is_ready_to_execute(executor, from_queue)
+
+    // moves a value, lets every site collect the slot it left and then deletes the value by the
+    // position it was inserted at
+    fn move_collect_delete(&mut self, executor: usize) {
+        let text = &self.data_structures[executor];
+        let Some(position) = text
+            .iter()
+            .filter(|(_, c)| c.is_some())
+            .map(|(pos, _c)| pos)
+            .choose(&mut self.rng)
+        else {
+            return;
+        };
+        let insert_after = text
+            .position_at(self.rng.gen_range(0..=text.len()))
+            .unwrap();
+        let op = self.data_structures[executor]
+            .local_move(position, insert_after)
+            .expect("Failed to move");
+        let OperationData::Move(moved) = &op.data else {
+            unreachable!("local_move returns a move");
+        };
+        let element = S4Vector::from(moved.position);
+        self.broadcast(executor, op);
+        self.execute_all_pending();
+        for site in 0..self.num_executors() {
+            self.collect_garbage(site);
+        }
+        let op = self.data_structures[executor]
+            .local_delete(element)
+            .expect("Failed to delete moved value");
+        self.broadcast(executor, op);
     }
 
-    fn has_pending_operations(&self, executor: usize, from_queue: usize) -> bool {
-        self.operation_positions[executor][from_queue] < self.pushed_operations[from_queue].len()
+    fn collect_garbage(&mut self, executor: usize) {
+        // acknowledgements are dropped if they overtake operations, just like a real transport would
+        for peer in 0..self.num_executors() {
+            let clock = self.data_structures[peer]
+                .get_clock()
+                .clock_values()
+                .to_vec();
+            self.data_structures[executor].acknowledge(peer, &clock);
+        }
+        self.data_structures[executor].collect_garbage();
     }
 
-    fn is_ready_to_execute(&self, executor: usize, from_queue: usize) -> bool {
-        let op_pos = self.operation_positions[executor][from_queue];
+    // state based synchronization, the operations of `other` in the inbox are applied already afterwards
+    fn merge(&mut self, executor: usize, other: usize) {
+        if executor == other {
+            return;
+        }
+        let (target, source) = if executor < other {
+            let (left, right) = self.data_structures.split_at_mut(other);
+            (&mut left[executor], &right[0])
+        } else {
+            let (left, right) = self.data_structures.split_at_mut(executor);
+            (&mut right[0], &left[other])
+        };
+        target.merge(source).expect("Failed to merge");
+    }
 
-        let op = &self.pushed_operations[from_queue][op_pos];
-        self.data_structures[executor].is_ready_to_receive(from_queue, &op.op_clock)
+    fn num_executors(&self) -> usize {
+        self.data_structures.len()
+    }
+    fn has_pending_operations(&self, executor: usize) -> bool {
+        !self.inboxes[executor].is_empty()
     }
 
-    fn execute_op(&mut self, executor: usize, from_queue: usize) -> bool {
-        let op_pos = self.operation_positions[executor][from_queue];
-        if !self.can_execute(executor, from_queue) {
+    // delivers a random operation from the inbox, the data structure has to take care of the causal order
+    // and of operations that are delivered more than once
+    fn execute_op(&mut self, executor: usize) -> bool {
+        if !self.has_pending_operations(executor) {
             return false;
         }
-        let op = &self.pushed_operations[from_queue][op_pos];
+        let op_pos = self.rng.gen_range(0..self.inboxes[executor].len());
+        let op = if self.rng.sample::<f32, _>(Standard) < self.redelivery_probability {
+            self.inboxes[executor][op_pos].clone()
+        } else {
+            self.inboxes[executor].swap_remove(op_pos)
+        };
 
         self.data_structures[executor]
             .apply_operation(&op)
             .expect("Failed to apply operation");
-        self.executed_operations[executor].push(op.clone());
-        self.operation_positions[executor][from_queue] += 1;
+        self.executed_operations[executor].push(op);
 
         true
     }
@@ -140,16 +235,7 @@ fn op_generation_scheme1(suite: &mut FuzzSuite, num_operations: usize) {
 }
 
 fn apply_random_op(suite: &mut FuzzSuite, executor: usize) -> bool {
-    let pending: Vec<usize> = (0..suite.num_executors())
-        .filter(|idx| suite.can_execute(executor, *idx))
-        .collect();
-    if pending.is_empty() {
-        return false;
-    }
-    let from_queue = *pending.choose(&mut suite.rng).unwrap();
-    suite.execute_op(executor, from_queue);
-
-    true
+    suite.execute_op(executor)
 }
 
 fn print_op_chain(operations: &[Operation]) {
@@ -164,8 +250,18 @@ fn main() {
 
     for iteration in 0..num_iterations {
         println!("iteration {}", iteration);
-        let mut suite = FuzzSuite::new(7, 0.8, 0.2);
+        let mut suite = FuzzSuite::new(7, 0.6, 0.2);
 
+        op_generation_scheme1(&mut suite, num_ops);
+        suite.execute_all_pending();
+        let executor = suite.rng.gen_range(0..suite.num_executors());
+        suite.move_collect_delete(executor);
+        // keep editing after some of the replicas removed their tombstones
+        for executor in 0..suite.num_executors() {
+            if suite.rng.gen_ratio(1, 2) {
+                suite.collect_garbage(executor);
+            }
+        }
         op_generation_scheme1(&mut suite, num_ops);
         suite.execute_all_pending();
         if suite.has_same_texts() {
@@ -181,9 +277,13 @@ fn main() {
             if text != text0 {
                 println!("expected: {}, received: {}", text0, text);
                 print_op_chain(&suite.executed_operations[ds]);
-                println!("{:?}", suite.operation_positions[ds]);
             }
         }
-        break;
+        panic!("text replicas diverged in iteration {}", iteration);
     }
+
+    assert!(
+        or_set::fuzz(num_iterations, num_ops),
+        "OR-set replicas diverged"
+    );
 }
@@ -0,0 +1,110 @@
+use rand::distributions::Standard;
+use rand::prelude::*;
+use rand::seq::IteratorRandom;
+
+use crdt::or_set::{ORSet, SetOperation};
+
+struct SetFuzzSuite {
+    sets: Vec<ORSet<u8>>,
+    inboxes: Vec<Vec<SetOperation<u8>>>,
+    rng: ThreadRng,
+    add_probability: f32,
+    redelivery_probability: f32,
+}
+
+impl SetFuzzSuite {
+    fn new(num_executors: usize, add_probability: f32) -> SetFuzzSuite {
+        SetFuzzSuite {
+            sets: (0..num_executors).map(ORSet::new).collect(),
+            inboxes: vec![vec![]; num_executors],
+            rng: thread_rng(),
+            add_probability,
+            redelivery_probability: 0.1,
+        }
+    }
+
+    fn perform_random_operation(&mut self, executor: usize) {
+        let set = &mut self.sets[executor];
+        // few distinct values so that adds and removes of the same value are concurrent often
+        let op = match set.iter().copied().choose(&mut self.rng) {
+            Some(value) if self.rng.sample::<f32, _>(Standard) >= self.add_probability => set
+                .local_remove(value)
+                .expect("Values of the set can be removed"),
+            _ => set.local_add(self.rng.gen_range(0..8)),
+        };
+        for (receiver, inbox) in self.inboxes.iter_mut().enumerate() {
+            if receiver != executor {
+                inbox.push(op.clone());
+            }
+        }
+    }
+
+    // delivers a random operation from the inbox, possibly again later
+    fn execute_op(&mut self, executor: usize) -> bool {
+        if self.inboxes[executor].is_empty() {
+            return false;
+        }
+        let op_pos = self.rng.gen_range(0..self.inboxes[executor].len());
+        let op = if self.rng.sample::<f32, _>(Standard) < self.redelivery_probability {
+            self.inboxes[executor][op_pos].clone()
+        } else {
+            self.inboxes[executor].swap_remove(op_pos)
+        };
+        self.sets[executor]
+            .apply_operation(&op)
+            .expect("Failed to apply operation");
+        true
+    }
+
+    fn execute_all_pending(&mut self) {
+        for executor in 0..self.sets.len() {
+            while self.execute_op(executor) {}
+            if !self.sets[executor].pending_operations().is_empty() {
+                panic!("Delivered all operations but some are still pending");
+            }
+        }
+    }
+
+    fn values(&self, executor: usize) -> Vec<u8> {
+        let mut values: Vec<u8> = self.sets[executor].iter().copied().collect();
+        values.sort();
+        values
+    }
+}
+
+/// Random adds and removes on several replicas, afterwards all replicas have to hold the same
+/// values. Returns false and prints the diverging replicas otherwise.
+pub fn fuzz(num_iterations: usize, num_ops: usize) -> bool {
+    for iteration in 0..num_iterations {
+        println!("set iteration {}", iteration);
+        let mut suite = SetFuzzSuite::new(5, 0.6);
+        let mut current_executor = 0;
+        for _ in 0..num_ops {
+            if suite.rng.gen_ratio(1, 10) {
+                current_executor = suite.rng.gen_range(0..suite.sets.len());
+            }
+            if suite.rng.gen_ratio(1, 2) && suite.execute_op(current_executor) {
+                continue;
+            }
+            suite.perform_random_operation(current_executor);
+        }
+        suite.execute_all_pending();
+
+        let values0 = suite.values(0);
+        let diverged: Vec<usize> = (1..suite.sets.len())
+            .filter(|executor| suite.values(*executor) != values0)
+            .collect();
+        if !diverged.is_empty() {
+            for executor in diverged {
+                println!(
+                    "expected: {:?}, replica {} has: {:?}",
+                    values0,
+                    executor,
+                    suite.values(executor)
+                );
+            }
+            return false;
+        }
+    }
+    true
+}
@@ -12,7 +12,10 @@ app.use(express.static('public'))
 // Set up a headless websocket server that prints any
 // events that come in.
 const wsServer = new ws.Server({ noServer: true });
+// every operation with the site that sent it and its sequence number, the
+// offsets of the operations of each site are kept in order of their numbers
 const messages = []
+const offsetsBySender = []
 const connections = []
 
 function syncNewMessages() {
@@ -30,21 +33,23 @@ function syncNewMessages() {
 }
 
 function syncClient(clientId) {
-    let offset = connections[clientId].currentOffset
-    console.debug("Synchronizing client", clientId, offset)
-
-    if (messages.length <= offset) {
-        return
+    let connection = connections[clientId]
+    let offset
+    if (connection.backlogIndex < connection.backlog.length) {
+        offset = connection.backlog[connection.backlogIndex++]
+    } else {
+        while (connection.currentOffset < messages.length && messages[connection.currentOffset].sentBy == clientId) {
+            connection.currentOffset++
+        }
+        if (messages.length <= connection.currentOffset) {
+            return
+        }
+        offset = connection.currentOffset++
     }
-    let msg = JSON.parse(messages[offset])
-
-    connections[clientId].currentOffset++
+    console.debug("Synchronizing client", clientId, offset)
 
-    if (msg.sent_by == clientId) {
-        return
-    }
-    connections[clientId].socket.send(
-        messages[offset],
+    connection.socket.send(
+        messages[offset].text,
         (err) => {
             if (err != undefined) {
                 console.log(err)
@@ -55,9 +60,58 @@ function syncClient(clientId) {
     )
 }
 
+// Reconnecting clients send the clock of their replica, operations it
+// already contains don't have to be sent again. Returns the offsets of the
+// stored operations of other sites the clock doesn't cover, in the order
+// they arrived.
+function missingOffsets(clientId, clock) {
+    let missing = []
+    offsetsBySender.forEach((offsets, sender) => {
+        if (sender == clientId) {
+            return
+        }
+        let seen = clock[sender] ?? 0
+        let low = 0
+        let high = offsets.length
+        while (low < high) {
+            let mid = (low + high) >> 1
+            if (messages[offsets[mid]].seq <= seen) {
+                low = mid + 1
+            } else {
+                high = mid
+            }
+        }
+        missing.push(...offsets.slice(low))
+    })
+    return missing.sort((a, b) => a - b)
+}
+
+function store(text, operation) {
+    let sentBy = operation.sent_by
+    offsetsBySender[sentBy] ??= []
+    offsetsBySender[sentBy].push(messages.length)
+    messages.push({ text, sentBy, seq: operation.op_clock[sentBy] })
+}
+
+// Awareness messages like cursor positions are only relayed to the clients
+// connected right now, unlike operations they are never stored or sent again.
+function relay(sender, message) {
+    for (let conn of connections) {
+        if (conn != undefined && conn.socket !== sender && conn.socket.readyState === ws.WebSocket.OPEN) {
+            conn.socket.send(message)
+        }
+    }
+}
+
 wsServer.on('connection', socket => {
     socket.on('message', message => {
-        messages.push(message.toString())
+        let text = message.toString()
+        let parsed = JSON.parse(text)
+        if (parsed.op_clock === undefined) {
+            relay(socket, text)
+            return
+        }
+        store(text, parsed)
         syncNewMessages()
     });
 });
@@ -74,8 +128,17 @@ server.on('upgrade', (request, socket, head) => {
     wsServer.handleUpgrade(request, socket, head, s => {
         wsServer.emit('connection', s, request);
 
-        let id = Number(request.url.substring(request.url.lastIndexOf("/") + 1))
-        connections[id] = { socket: s, currentOffset: 0, id }
+        let url = new URL(request.url, `http://${request.headers.host}`)
+        let id = Number(url.pathname.substring(url.pathname.lastIndexOf("/") + 1))
+        let clock = JSON.parse(url.searchParams.get('clock') ?? '[]')
+        // the stored operations are sent first, then those arriving from now on
+        connections[id] = {
+            socket: s,
+            id,
+            backlog: missingOffsets(id, clock),
+            backlogIndex: 0,
+            currentOffset: messages.length,
+        }
         syncClient(id)
     });
 });